@@ -1,10 +1,11 @@
-use std::fmt::Display;
-
 use anyhow::Result;
 use chat_common::{ChatServiceClient, MessageMetaData, RoomDescription};
 use egui::{Color32, Key, RichText, ScrollArea, TextEdit, Ui};
-use egui_shortcuts::{spawn_promise, Promise, SimpleSpawner};
-use framework::{BiStreamProxy, ClientFramework};
+use egui_shortcuts::SimpleSpawner;
+use framework::session::AnySession;
+use framework::{
+    BiStreamProxy, ClientFramework, ConnectionState, ReconnectOptions, ReconnectingClient,
+};
 
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
@@ -75,14 +76,8 @@ fn main() {
     });
 }
 
-#[derive(Clone)]
-struct Connection {
-    frame: ClientFramework,
-    client: ChatServiceClient,
-}
-
 pub struct ChatApp {
-    sess: Promise<Result<Connection>>,
+    conn: ReconnectingClient<ChatServiceClient>,
     new_room_name: String,
     msg_edit: String,
     username: String,
@@ -92,33 +87,19 @@ pub struct ChatApp {
 impl ChatApp {
     /// Called once before the first frame.
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        let egui_ctx = cc.egui_ctx.clone();
-
-        let sess = spawn_promise(async move {
-            // Get framework and channel
-            let url = url::Url::parse("https://127.0.0.1:9090/")?;
+        let conn = ReconnectingClient::new(connect, ReconnectOptions::default());
 
-            let sess = quic_session::client_session_selfsigned(
-                &url,
-                chat_common::CERTIFICATE.to_vec(),
-                chat_common::CERTIFICATE_HASHES.to_vec(),
-            )
-            .await?;
-
-            let (frame, channel) = ClientFramework::new(sess).await?;
-
-            // Get root client
-            let newclient = ChatServiceClient::new(Default::default(), channel);
-            framework::spawn(newclient.dispatch);
-            let client = newclient.client;
-
-            egui_ctx.request_repaint();
-
-            Ok(Connection { frame, client })
+        // Show every change of the connection state
+        let mut state = conn.state();
+        let egui_ctx = cc.egui_ctx.clone();
+        framework::spawn(async move {
+            while state.changed().await.is_ok() {
+                egui_ctx.request_repaint();
+            }
         });
 
         Self {
-            sess,
+            conn,
             color: [0xff; 3],
             msg_edit: "".into(),
             username: "my_username".into(),
@@ -127,11 +108,46 @@ impl ChatApp {
     }
 }
 
-fn connection_status<T: Send, E: Display + Send>(ui: &mut Ui, prom: &Promise<Result<T, E>>) {
-    match prom.ready() {
-        None => ui.label("Connecting"),
-        Some(Ok(_)) => ui.label("Connection open"),
-        Some(Err(e)) => ui.label(format!("Error: {e:#}")),
+async fn connect() -> Result<(ClientFramework, ChatServiceClient)> {
+    // Get framework and channel
+    let url = url::Url::parse("https://127.0.0.1:9090/")?;
+
+    let sess: AnySession = match quic_session::client_session_selfsigned(
+        &url,
+        chat_common::CERTIFICATE.to_vec(),
+        chat_common::CERTIFICATE_HASHES.to_vec(),
+    )
+    .await
+    {
+        Ok(sess) => sess.into(),
+        Err(e) => {
+            log::warn!("WebTransport failed ({e:#}), falling back to WebSocket");
+            framework::websocket::connect("ws://127.0.0.1:9091/")
+                .await?
+                .into()
+        }
+    };
+
+    let (frame, channel) = ClientFramework::new(sess).await?;
+
+    // Get root client
+    let newclient = ChatServiceClient::new(Default::default(), channel);
+    framework::spawn(newclient.dispatch);
+
+    Ok((frame, newclient.client))
+}
+
+fn connection_status(ui: &mut Ui, state: &ConnectionState) {
+    match state {
+        ConnectionState::Connecting { attempt: 1 } => ui.label("Connecting"),
+        ConnectionState::Connecting { attempt } => {
+            ui.label(format!("Connecting (attempt {attempt})"))
+        }
+        ConnectionState::Connected => ui.label("Connection open"),
+        ConnectionState::Disconnected { error, retry_in } => ui.label(format!(
+            "Error: {error}, retrying in {:.1}s",
+            retry_in.as_secs_f32()
+        )),
     };
 }
 
@@ -164,9 +180,9 @@ impl eframe::App for ChatApp {
             ui.separator();
 
             ui.strong("Connection status");
-            connection_status(ui, &self.sess);
+            connection_status(ui, &self.conn.state().borrow());
 
-            let Some(Ok(sess)) = self.sess.ready_mut() else {
+            let Some(sess) = self.conn.current() else {
                 return;
             };
             let rooms_spawner = SimpleSpawner::new("rooms_spawner");
@@ -202,7 +218,7 @@ impl eframe::App for ChatApp {
 
                             let egui_ctx = ui.ctx().clone();
                             let name = name.clone();
-                            let frame = sess.frame.clone();
+                            let frame = sess.framework.clone();
                             chat_spawner.spawn(ui, async move {
                                 let stream = client_clone.chat(ctx, name.clone()).await??;
                                 let stream = BiStreamProxy::new(stream, frame, move || {
@@ -5,12 +5,9 @@ use anyhow::Result;
 use chat_common::*;
 use framework::futures::{Sink, SinkExt};
 use framework::io::FrameworkError;
-use framework::tarpc::context::Context as TarpcContext;
-use framework::{
-    futures::StreamExt,
-    tarpc::server::{BaseChannel, Channel},
-    ServerFramework,
-};
+use framework::server::RootChannel;
+use framework::tarpc::{context::Context as TarpcContext, server::Channel};
+use framework::{futures::StreamExt, ServerFramework};
 use tokio::sync::mpsc::Sender as TokioSender;
 use tokio::sync::Mutex as TokioMutex;
 
@@ -32,13 +29,17 @@ async fn main() -> Result<()> {
 async fn chat_server() -> Result<()> {
     log::info!("Chat server");
 
-    let mut endpoint = quic_session::server_endpoint(
+    let endpoint = quic_session::server_endpoint(
         "0.0.0.0:9090".parse().unwrap(),
         chat_common::CERTIFICATE.to_vec(),
         DEFINITELY_NOT_THE_PRIVATE_KEY.to_vec(),
     )
     .await?;
 
+    // For clients which can't use WebTransport
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:9091").await?;
+    log::info!("listening for WebSockets on {}", listener.local_addr()?);
+
     let mut shared = SharedData::default();
     shared
         .create_room(RoomDescription {
@@ -48,31 +49,20 @@ async fn chat_server() -> Result<()> {
         .await;
 
     let shared = Arc::new(TokioMutex::new(shared));
+    let service = move |framework: ServerFramework, channel: RootChannel<_, _>| {
+        channel.execute(ChatService::serve(ChatServer::new(
+            framework,
+            shared.clone(),
+        )))
+    };
 
-    while let Some(inc) = endpoint.accept().await {
-        log::info!("New connection");
-        let shared = shared.clone();
-
-        tokio::spawn(async move {
-            let sess = quic_session::server_connect(inc).await?;
-
-            // Spawn the root service
-            let (framework, channel) = ServerFramework::new(sess).await?;
-            let transport = BaseChannel::with_defaults(channel);
-
-            let server = ChatServer::new(framework, shared);
-
-            let executor = transport.execute(ChatService::serve(server));
-
-            tokio::spawn(executor.for_each(|response| async move {
-                tokio::spawn(response);
-            }));
+    // Both shut down gracefully on Ctrl+C or SIGTERM
+    tokio::join!(
+        framework::server::serve(framework::webtransport::incoming(endpoint), service.clone()),
+        framework::server::serve(framework::websocket::incoming(listener), service),
+    );
 
-            log::info!("Connection ended");
-
-            Ok::<_, anyhow::Error>(())
-        });
-    }
+    log::info!("Chat server stopped");
 
     Ok(())
 }
@@ -99,6 +89,13 @@ struct Room {
 
 impl ChatServer {
     pub fn new(framework: ServerFramework, shared: Arc<TokioMutex<SharedData>>) -> Self {
+        if let Some(peer_info) = framework.peer_info() {
+            log::info!(
+                "Session {} from {:?}",
+                peer_info.session_id,
+                peer_info.remote_addr
+            );
+        }
         Self { framework, shared }
     }
 }
@@ -122,6 +119,10 @@ impl ChatService for ChatServer {
         _context: TarpcContext,
         room_name: String,
     ) -> Result<framework::BiStream<MessageMetaData, MessageMetaData>, ChatError> {
+        if let Some(peer_info) = self.framework.peer_info() {
+            log::info!("Session {} joined {room_name}", peer_info.session_id);
+        }
+
         let (handle, streamfut) = self.framework.accept_bistream();
 
         let shared = self.shared.clone();
@@ -18,3 +18,6 @@ pub trait MyOtherService {
     /// Subtracts numbers
     async fn subtract(a: u32, b: u32) -> u32;
 }
+
+framework::impl_service_client!(MyService);
+framework::impl_service_client!(MyOtherService);
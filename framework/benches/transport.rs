@@ -0,0 +1,170 @@
+//! Compares `webtransport_protocol` against the previous implementation, which framed messages
+//! with `LengthDelimitedCodec` on top of `webtransport_futures_bridge`. Their wire formats
+//! differ (`webtransport_protocol` sends a compression preamble and flags each frame), so each
+//! is measured against its own echo server, on a local QUIC connection, using the same framing.
+//!
+//! Run with `cargo bench -p framework`
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use framework::codec::Bincode;
+use framework::futures::{Sink, SinkExt, Stream, StreamExt};
+use framework::io::{
+    decode, encode, webtransport_futures_bridge, webtransport_protocol, FrameOptions,
+    FrameworkError,
+};
+use tokio::runtime::Runtime;
+use tokio_util::codec::{Decoder, LengthDelimitedCodec};
+use url::Url;
+use web_transport::{RecvStream, SendStream, Session};
+
+type Msg = Vec<u8>;
+
+/// One per framing, in the order of `framings()`
+const LATENCY_PORTS: [u16; 2] = [9443, 9444];
+const THROUGHPUT_PORTS: [u16; 2] = [9445, 9446];
+const LATENCY_MSG_SIZE: usize = 64;
+const THROUGHPUT_MSG_SIZES: &[usize] = &[1024, 64 * 1024, 1024 * 1024];
+
+trait Transport:
+    Sink<Msg, Error = FrameworkError> + Stream<Item = Result<Msg, FrameworkError>>
+{
+}
+
+impl<T> Transport for T where
+    T: Sink<Msg, Error = FrameworkError> + Stream<Item = Result<Msg, FrameworkError>>
+{
+}
+
+/// Wraps a bidirectional stream in one of the implementations being compared
+type Framing = fn((SendStream, RecvStream)) -> Box<dyn Transport + Unpin + Send>;
+
+/// Framing as it was done before, through the spawned bridge tasks
+fn bridged(socks: (SendStream, RecvStream)) -> impl Transport {
+    LengthDelimitedCodec::default()
+        .framed(webtransport_futures_bridge(socks))
+        .sink_map_err(FrameworkError::from)
+        .with(|obj: Msg| async move { Ok::<_, FrameworkError>(Bytes::from(encode(&obj)?)) })
+        .map(|frame| decode::<Msg>(&frame?))
+}
+
+fn direct(socks: (SendStream, RecvStream)) -> impl Transport {
+    webtransport_protocol(socks, Bincode, FrameOptions::default())
+}
+
+/// Starts an echo server which uses `framing`, and returns a session connected to it
+async fn connect(port: u16, framing: Framing) -> Session {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_pem = cert.cert.pem().into_bytes();
+    let key_pem = cert.key_pair.serialize_pem().into_bytes();
+
+    let mut endpoint =
+        quic_session::server_endpoint(([127, 0, 0, 1], port).into(), cert_pem.clone(), key_pem)
+            .await
+            .unwrap();
+
+    tokio::spawn(async move {
+        while let Some(inc) = endpoint.accept().await {
+            let mut sess = quic_session::server_connect(inc).await.unwrap();
+            tokio::spawn(async move {
+                while let Ok(socks) = sess.accept_bi().await {
+                    tokio::spawn(echo(framing(socks)));
+                }
+            });
+        }
+    });
+
+    let url = Url::parse(&format!("https://localhost:{port}")).unwrap();
+    quic_session::client_session(&url, cert_pem).await.unwrap()
+}
+
+async fn echo(transport: impl Transport) {
+    let (mut tx, mut rx) = transport.split();
+    while let Some(Ok(msg)) = rx.next().await {
+        if tx.send(msg).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Time taken for `iters` round trips of a small message
+async fn round_trips(mut sess: Session, framing: Framing, iters: u64) -> Duration {
+    let mut transport = framing(sess.open_bi().await.unwrap());
+    let msg = vec![0xAB; LATENCY_MSG_SIZE];
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        transport.send(msg.clone()).await.unwrap();
+        transport.next().await.unwrap().unwrap();
+    }
+    start.elapsed()
+}
+
+/// Time taken to echo `iters` messages of `size` bytes, sending and receiving concurrently
+async fn pipelined(mut sess: Session, framing: Framing, size: usize, iters: u64) -> Duration {
+    let transport = framing(sess.open_bi().await.unwrap());
+    let (mut tx, mut rx) = transport.split();
+    let msg = vec![0xAB; size];
+
+    let start = Instant::now();
+    let sending = async {
+        for _ in 0..iters {
+            tx.feed(msg.clone()).await.unwrap();
+        }
+        tx.flush().await.unwrap();
+    };
+    let receiving = async {
+        for _ in 0..iters {
+            rx.next().await.unwrap().unwrap();
+        }
+    };
+    framework::futures::join!(sending, receiving);
+    start.elapsed()
+}
+
+fn framings() -> [(&'static str, Framing); 2] {
+    [
+        ("bridged", |socks| Box::new(Box::pin(bridged(socks)))),
+        ("direct", |socks| Box::new(Box::pin(direct(socks)))),
+    ]
+}
+
+fn latency(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("latency");
+    for ((name, framing), port) in framings().into_iter().zip(LATENCY_PORTS) {
+        let sess = rt.block_on(connect(port, framing));
+        group.bench_function(name, |b| {
+            b.to_async(&rt)
+                .iter_custom(|iters| round_trips(sess.clone(), framing, iters));
+        });
+    }
+    group.finish();
+}
+
+fn throughput(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let servers: Vec<_> = framings()
+        .into_iter()
+        .zip(THROUGHPUT_PORTS)
+        .map(|((name, framing), port)| (name, framing, rt.block_on(connect(port, framing))))
+        .collect();
+
+    let mut group = c.benchmark_group("throughput");
+    for &size in THROUGHPUT_MSG_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        for &(name, framing, ref sess) in &servers {
+            group.bench_with_input(BenchmarkId::new(name, size), &size, |b, &size| {
+                b.to_async(&rt)
+                    .iter_custom(|iters| pipelined(sess.clone(), framing, size, iters));
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, latency, throughput);
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+use futures::Future;
+
+use crate::io::{decode, encode, read_frame, write_frame, FrameworkError};
+use crate::session::{AnyRecvStream, AnySendStream};
+use crate::MaybeSend;
+
+/// Code the server closes the session with when it rejects the client's credentials
+pub const AUTH_FAILED_CODE: u32 = 0x41;
+
+/// Largest verdict we accept from the server
+const MAX_VERDICT_SIZE: usize = 4096;
+
+/// Who the client is, as decided by the server's `Authenticator`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+}
+
+/// Checks the credentials which a client puts in its `FrameworkConfig`, for
+/// `ServerFramework::new_with_auth()`
+pub trait Authenticator: MaybeSend + 'static {
+    /// Returns who the client is, or why it was rejected. The reason is sent to the client.
+    fn authenticate(
+        &self,
+        credentials: Option<&str>,
+    ) -> impl Future<Output = Result<Identity, String>> + MaybeSend;
+}
+
+/// The server tells the client whether it was let in, right after the handshake. The session is
+/// closed with `AUTH_FAILED_CODE` right after a rejection.
+pub(crate) async fn send_verdict(
+    tx: &mut AnySendStream,
+    verdict: &Result<(), String>,
+) -> Result<(), FrameworkError> {
+    write_frame(tx, &encode(verdict)?, MAX_VERDICT_SIZE).await
+}
+
+/// Fails with `FrameworkError::Unauthorized` if the server rejected our credentials
+pub(crate) async fn receive_verdict(rx: &mut AnyRecvStream) -> Result<(), FrameworkError> {
+    let Some(frame) = read_frame(rx, MAX_VERDICT_SIZE).await? else {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    };
+    decode::<Result<(), String>>(&frame)?.map_err(FrameworkError::Unauthorized)
+}
@@ -0,0 +1,148 @@
+use std::{sync::Arc, time::Duration};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+// The standard library's clock panics in the browser
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use crate::io::{decode, encode, read_frame, write_frame, FrameworkError};
+use crate::router::StreamId;
+use crate::session::{AnyRecvStream, AnySendStream, RecvStream};
+
+/// Error code sent to the peer when it redeems a token with a capability we can't verify
+pub const CAPABILITY_REJECTED_CODE: u32 = 0x52;
+
+/// Largest capability we accept from the peer
+const MAX_CAPABILITY_SIZE: usize = 4096;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret which the issuer signs capabilities with. Keep it on the server.
+#[derive(Clone)]
+pub struct CapabilityKey(Arc<[u8]>);
+
+impl CapabilityKey {
+    pub fn new(secret: &[u8]) -> Self {
+        Self(secret.into())
+    }
+}
+
+/// Proof that the issuer granted a token, carried by the token and presented again when it is
+/// redeemed. It is only valid on the session which issued it, until it expires.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Capability {
+    scope: String,
+    /// Seconds since the Unix epoch
+    expires_at: u64,
+    session: u64,
+    mac: [u8; 32],
+}
+
+impl Capability {
+    /// What the token grants, as set by the issuer
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    /// When the token can no longer be redeemed
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires_at)
+    }
+}
+
+/// Signs the capabilities of the tokens issued by a handle from `using_capability()`
+#[derive(Clone)]
+pub(crate) struct Signer {
+    pub key: CapabilityKey,
+    pub scope: String,
+    pub ttl: Duration,
+    /// Random for each session, so that tokens can't be replayed on another one
+    pub session: u64,
+}
+
+impl Signer {
+    pub fn sign(&self, id: StreamId) -> Capability {
+        let expires_at = unix_now().saturating_add(self.ttl.as_secs());
+        let mac = self
+            .mac(id, &self.scope, expires_at, self.session)
+            .finalize()
+            .into_bytes()
+            .into();
+
+        Capability {
+            scope: self.scope.clone(),
+            expires_at,
+            session: self.session,
+            mac,
+        }
+    }
+
+    /// Reads the capability which the holder presents on the stream, stopping the stream if
+    /// it isn't the one we signed for this token
+    pub async fn verify(&self, id: StreamId, rx: &mut AnyRecvStream) -> Result<(), FrameworkError> {
+        let result = async {
+            let Some(frame) = read_frame(rx, MAX_CAPABILITY_SIZE).await? else {
+                return Err(rejected("missing"));
+            };
+            let capability = decode::<Capability>(&frame)?;
+
+            let mac = self.mac(
+                id,
+                &capability.scope,
+                capability.expires_at,
+                capability.session,
+            );
+            if mac.verify_slice(&capability.mac).is_err() {
+                return Err(rejected("bad signature"));
+            }
+            if capability.session != self.session {
+                return Err(rejected("issued on another session"));
+            }
+            if capability.expires_at < unix_now() {
+                return Err(rejected("expired"));
+            }
+            Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            rx.stop(CAPABILITY_REJECTED_CODE);
+        }
+        result
+    }
+
+    fn mac(&self, id: StreamId, scope: &str, expires_at: u64, session: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key.0).expect("HMAC takes keys of any size");
+        mac.update(&id.0.to_le_bytes());
+        mac.update(&expires_at.to_le_bytes());
+        mac.update(&session.to_le_bytes());
+        mac.update(scope.as_bytes());
+        mac
+    }
+}
+
+/// Presents the token's capability to the issuer, right after the stream header
+pub(crate) async fn present(
+    tx: &mut AnySendStream,
+    capability: &Capability,
+) -> Result<(), FrameworkError> {
+    write_frame(tx, &encode(capability)?, MAX_CAPABILITY_SIZE).await
+}
+
+/// A fresh random number from the OS, or from the browser's `crypto.getRandomValues()`
+pub(crate) fn random_session() -> u64 {
+    let mut bytes = [0; 8];
+    getrandom::getrandom(&mut bytes).expect("No random number generator");
+    u64::from_le_bytes(bytes)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn rejected(reason: &str) -> FrameworkError {
+    FrameworkError::CapabilityRejected(reason.into())
+}
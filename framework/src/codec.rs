@@ -0,0 +1,309 @@
+use std::error::Error;
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::io::{FrameworkError, DEFAULT_MAX_DECODE_ALLOCATION};
+
+/// Turns messages into bytes and back. Both ends of a stream must use the same codec, so each
+/// codec has a name which is compared with the peer's.
+pub trait Codec: Clone + Send + Sync + 'static {
+    fn name(&self) -> &'static str;
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameworkError>;
+
+    /// Decodes a message, failing with `FrameworkError::Decode` once its strings and
+    /// collections claim more than `max_allocation` bytes, whatever their length prefixes say
+    fn decode_limited<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        max_allocation: usize,
+    ) -> Result<T, FrameworkError>;
+
+    /// `decode_limited()` with `DEFAULT_MAX_DECODE_ALLOCATION`
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FrameworkError> {
+        self.decode_limited(bytes, DEFAULT_MAX_DECODE_ALLOCATION)
+    }
+}
+
+fn encode_error<T>(source: impl Into<Box<dyn Error + Send + Sync>>) -> FrameworkError {
+    FrameworkError::Encode {
+        type_name: std::any::type_name::<T>(),
+        source: source.into(),
+    }
+}
+
+fn decode_error<T>(source: impl Into<Box<dyn Error + Send + Sync>>) -> FrameworkError {
+    FrameworkError::Decode {
+        type_name: std::any::type_name::<T>(),
+        source: source.into(),
+    }
+}
+
+/// Decodes with bincode, reading strings and buffers straight from `bytes`
+fn decode_bincode<T: DeserializeOwned>(
+    bytes: &[u8],
+    config: impl bincode::config::Config,
+    max_allocation: usize,
+) -> Result<T, FrameworkError> {
+    let mut decoder = bincode::serde::BorrowedSerdeDecoder::<
+        bincode::de::DecoderImpl<bincode::de::read::SliceReader, _, ()>,
+    >::from_slice(bytes, config, ());
+    crate::limit::deserialize(decoder.as_deserializer(), max_allocation).map_err(decode_error::<T>)
+}
+
+/// Bincode with fixed size little endian integers. This is the default, and is also what the
+/// framework uses for its own headers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameworkError> {
+        crate::io::encode(value)
+    }
+
+    fn decode_limited<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        max_allocation: usize,
+    ) -> Result<T, FrameworkError> {
+        decode_bincode(bytes, crate::io::config(), max_allocation)
+    }
+}
+
+/// Bincode with variable length integers, which is smaller for typical data
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeVarint;
+
+impl Codec for BincodeVarint {
+    fn name(&self) -> &'static str {
+        "bincode-varint"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameworkError> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard()).map_err(encode_error::<T>)
+    }
+
+    fn decode_limited<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        max_allocation: usize,
+    ) -> Result<T, FrameworkError> {
+        decode_bincode(bytes, bincode::config::standard(), max_allocation)
+    }
+}
+
+/// JSON, for reading the traffic while debugging
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameworkError> {
+        serde_json::to_vec(value).map_err(encode_error::<T>)
+    }
+
+    fn decode_limited<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        max_allocation: usize,
+    ) -> Result<T, FrameworkError> {
+        let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+        let value = crate::limit::deserialize(&mut deserializer, max_allocation)
+            .map_err(decode_error::<T>)?;
+        deserializer.end().map_err(decode_error::<T>)?;
+        Ok(value)
+    }
+}
+
+#[cfg(feature = "postcard")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn name(&self) -> &'static str {
+        "postcard"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameworkError> {
+        postcard::to_allocvec(value).map_err(encode_error::<T>)
+    }
+
+    fn decode_limited<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        max_allocation: usize,
+    ) -> Result<T, FrameworkError> {
+        let mut deserializer = postcard::Deserializer::from_bytes(bytes);
+        crate::limit::deserialize(&mut deserializer, max_allocation).map_err(decode_error::<T>)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameworkError> {
+        rmp_serde::to_vec(value).map_err(encode_error::<T>)
+    }
+
+    fn decode_limited<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        max_allocation: usize,
+    ) -> Result<T, FrameworkError> {
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes);
+        crate::limit::deserialize(&mut deserializer, max_allocation).map_err(decode_error::<T>)
+    }
+}
+
+/// One of the built-in codecs, chosen at runtime. This is what the frameworks use; tokens carry
+/// the codec picked by the side which issued them (by id), so the holder uses the same one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnyCodec {
+    #[default]
+    Bincode,
+    BincodeVarint,
+    Json,
+    #[cfg(feature = "postcard")]
+    Postcard,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl AnyCodec {
+    const ALL: &'static [Self] = &[
+        Self::Bincode,
+        Self::BincodeVarint,
+        Self::Json,
+        #[cfg(feature = "postcard")]
+        Self::Postcard,
+        #[cfg(feature = "msgpack")]
+        Self::MessagePack,
+    ];
+
+    /// Looks up a codec by its `Codec::name()`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|codec| codec.name() == name)
+    }
+
+    /// Identifies the codec in tokens. Fixed for each codec, whichever features are enabled.
+    fn id(&self) -> u8 {
+        match self {
+            Self::Bincode => 0,
+            Self::BincodeVarint => 1,
+            Self::Json => 2,
+            #[cfg(feature = "postcard")]
+            Self::Postcard => 3,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => 4,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|codec| codec.id() == id)
+    }
+}
+
+impl Codec for AnyCodec {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Bincode => Bincode.name(),
+            Self::BincodeVarint => BincodeVarint.name(),
+            Self::Json => Json.name(),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard.name(),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack.name(),
+        }
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameworkError> {
+        match self {
+            Self::Bincode => Bincode.encode(value),
+            Self::BincodeVarint => BincodeVarint.encode(value),
+            Self::Json => Json.encode(value),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard.encode(value),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack.encode(value),
+        }
+    }
+
+    fn decode_limited<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        max_allocation: usize,
+    ) -> Result<T, FrameworkError> {
+        match self {
+            Self::Bincode => Bincode.decode_limited(bytes, max_allocation),
+            Self::BincodeVarint => BincodeVarint.decode_limited(bytes, max_allocation),
+            Self::Json => Json.decode_limited(bytes, max_allocation),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard.decode_limited(bytes, max_allocation),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack.decode_limited(bytes, max_allocation),
+        }
+    }
+}
+
+// Serialized by id, so that peers built with different codec features still agree
+impl Serialize for AnyCodec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.id())
+    }
+}
+
+impl<'de> Deserialize<'de> for AnyCodec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = u8::deserialize(deserializer)?;
+        Self::from_id(id).ok_or_else(|| serde::de::Error::custom(format!("unsupported codec {id}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_codec_enforces_the_decode_limit() {
+        let message = ("name".to_string(), vec![7_u64; 10]);
+
+        for codec in AnyCodec::ALL {
+            let bytes = codec.encode(&message).unwrap();
+            let decoded: (String, Vec<u64>) = codec.decode_limited(&bytes, 1000).unwrap();
+            assert_eq!(decoded, message, "{}", codec.name());
+
+            let error = codec.decode_limited::<(String, Vec<u64>)>(&bytes, 50);
+            assert!(
+                matches!(error, Err(FrameworkError::Decode { .. })),
+                "{}",
+                codec.name()
+            );
+        }
+    }
+
+    #[test]
+    fn length_prefixes_are_not_trusted() {
+        // A string claiming a terabyte, in a frame of a few bytes
+        let len = 1_u64 << 40;
+        let fixint = len.to_le_bytes().to_vec();
+        let varint = [&[253][..], &len.to_le_bytes()].concat();
+
+        assert!(Bincode.decode::<String>(&fixint).is_err());
+        assert!(BincodeVarint.decode::<String>(&varint).is_err());
+        assert!(BincodeVarint.decode::<Vec<u8>>(&varint).is_err());
+    }
+}
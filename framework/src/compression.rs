@@ -0,0 +1,144 @@
+use bytes::Bytes;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::io::FrameworkError;
+
+/// Frames smaller than this are sent uncompressed unless configured otherwise, since they
+/// rarely shrink by enough to be worth it
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Flag in front of each frame on a compressed stream
+#[cfg(feature = "lz4")]
+const FRAME_RAW: u8 = 0;
+#[cfg(feature = "lz4")]
+const FRAME_LZ4: u8 = 1;
+
+/// Compression of the frames on a stream. Each stream starts with the id of its compression,
+/// so that a peer which doesn't agree fails with `FrameworkError::ProtocolMismatch` instead of
+/// decoding garbage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    const ALL: &'static [Self] = &[
+        Self::None,
+        #[cfg(feature = "lz4")]
+        Self::Lz4,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    /// Looks up a compression by its `name()`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    /// Identifies the compression at the start of a stream
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.id() == id)
+    }
+
+    /// Bytes a frame may carry besides the encoded message: the flag in front of each frame on a
+    /// compressed stream. `FrameOptions::max_frame_size` doesn't count them.
+    pub(crate) fn frame_overhead(&self) -> usize {
+        match self {
+            Self::None => 0,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => 1,
+        }
+    }
+
+    /// Turns an encoded message into a frame. Payloads below `threshold` bytes, and those which
+    /// don't get any smaller, are sent as they are.
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    pub(crate) fn compress(&self, payload: Vec<u8>, threshold: usize) -> Vec<u8> {
+        match self {
+            Self::None => payload,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                if payload.len() >= threshold {
+                    let mut frame = vec![FRAME_LZ4];
+                    frame.extend(lz4_flex::compress_prepend_size(&payload));
+                    if frame.len() <= payload.len() {
+                        return frame;
+                    }
+                }
+                raw_frame(payload)
+            }
+        }
+    }
+
+    /// Turns a frame back into an encoded message, refusing to inflate it past `max_size`
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    pub(crate) fn decompress(
+        &self,
+        frame: Bytes,
+        max_size: usize,
+    ) -> Result<Bytes, FrameworkError> {
+        match self {
+            Self::None => Ok(frame),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => match frame.first() {
+                Some(&FRAME_RAW) => Ok(frame.slice(1..)),
+                Some(&FRAME_LZ4) => {
+                    let (size, compressed) = lz4_flex::block::uncompressed_size(&frame[1..])
+                        .map_err(|e| FrameworkError::Decompress(e.into()))?;
+                    if size > max_size {
+                        return Err(FrameworkError::FrameTooLarge {
+                            size,
+                            max: max_size,
+                        });
+                    }
+                    lz4_flex::decompress(compressed, size)
+                        .map(Bytes::from)
+                        .map_err(|e| FrameworkError::Decompress(e.into()))
+                }
+                flag => Err(FrameworkError::Decompress(
+                    format!("unknown frame flag {flag:?}").into(),
+                )),
+            },
+        }
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn raw_frame(payload: Vec<u8>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + payload.len());
+    frame.push(FRAME_RAW);
+    frame.extend(payload);
+    frame
+}
+
+// Serialized by id, like `AnyCodec`
+impl Serialize for Compression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.id())
+    }
+}
+
+impl<'de> Deserialize<'de> for Compression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = u8::deserialize(deserializer)?;
+        Self::from_id(id)
+            .ok_or_else(|| serde::de::Error::custom(format!("unsupported compression {id}")))
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::{channel::mpsc, Future, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    codec::{AnyCodec, Codec},
+    io::{decode, encode, FrameworkError, STREAM_HEADER_SIZE},
+    router::{StreamHeader, StreamId},
+    session::{AnySession, Session},
+    MaybeSend,
+};
+
+/// Largest datagram we are willing to send, including the header. QUIC guarantees at least
+/// 1200 bytes per packet, which leaves some room for the QUIC and WebTransport overhead.
+pub const MAX_DATAGRAM_SIZE: usize = 1100;
+
+/// Number of received datagrams buffered per channel before new ones are dropped
+const DATAGRAM_QUEUE_LEN: usize = 64;
+
+/// Multiplexes the datagrams of a session between the channels open on it
+#[derive(Clone)]
+pub(crate) struct DatagramRouter {
+    sess: AnySession,
+    state: Arc<Mutex<DatagramRouterState>>,
+}
+
+#[derive(Default)]
+struct DatagramRouterState {
+    /// Channels for tokens we handed out
+    issued: HashMap<StreamId, mpsc::Sender<Bytes>>,
+    /// Channels for tokens we were handed
+    redeemed: HashMap<StreamId, mpsc::Sender<Bytes>>,
+    /// Whether the receive loop was started
+    running: bool,
+}
+
+impl DatagramRouterState {
+    fn table(&mut self, issued: bool) -> &mut HashMap<StreamId, mpsc::Sender<Bytes>> {
+        if issued {
+            &mut self.issued
+        } else {
+            &mut self.redeemed
+        }
+    }
+}
+
+impl DatagramRouter {
+    pub fn new(sess: AnySession) -> Self {
+        Self {
+            sess,
+            state: Default::default(),
+        }
+    }
+
+    /// Creates the local end of a datagram channel
+    pub fn channel<Tx, Rx>(
+        &self,
+        id: StreamId,
+        issued: bool,
+        codec: AnyCodec,
+    ) -> DatagramChannel<Tx, Rx> {
+        let (tx, channel) = self.create(id, issued, codec);
+        self.admit(id, issued, tx);
+        channel
+    }
+
+    /// Creates the local end of a channel for a token we issued, which only receives once
+    /// `admitted` succeeds, e.g. once the holder has presented the token's capability
+    /// Warning: spawns tasks underneath
+    pub fn channel_after<Tx, Rx>(
+        &self,
+        id: StreamId,
+        codec: AnyCodec,
+        admitted: impl Future<Output = Result<(), FrameworkError>> + MaybeSend + 'static,
+    ) -> DatagramChannel<Tx, Rx> {
+        let (tx, channel) = self.create(id, true, codec);
+        let router = self.clone();
+        crate::spawn(async move {
+            admitted.await?;
+            router.admit(id, true, tx);
+            Ok::<_, FrameworkError>(())
+        });
+        channel
+    }
+
+    /// Starts routing datagrams to a channel, unless it was dropped already
+    fn admit(&self, id: StreamId, issued: bool, tx: mpsc::Sender<Bytes>) {
+        let mut state = self.state.lock().unwrap();
+        if tx.is_closed() {
+            return;
+        }
+        state.table(issued).insert(id, tx);
+
+        // Only receive datagrams once someone is interested in them
+        if !state.running {
+            state.running = true;
+            self.run();
+        }
+    }
+
+    fn create<Tx, Rx>(
+        &self,
+        id: StreamId,
+        issued: bool,
+        codec: AnyCodec,
+    ) -> (mpsc::Sender<Bytes>, DatagramChannel<Tx, Rx>) {
+        let (tx, rx) = mpsc::channel(DATAGRAM_QUEUE_LEN);
+        let channel = DatagramChannel {
+            router: self.clone(),
+            header: StreamHeader {
+                id,
+                from_issuer: issued,
+            },
+            incoming: rx,
+            codec,
+            _phantom: PhantomData,
+        };
+        (tx, channel)
+    }
+
+    /// Receives datagrams until the session closes
+    /// Warning: spawns tasks underneath
+    fn run(&self) {
+        let router = self.clone();
+        let sess = self.sess.clone();
+        crate::spawn(async move {
+            let err = loop {
+                match sess.recv_datagram().await {
+                    Ok(datagram) => router.dispatch(datagram),
+                    Err(e) => break e,
+                }
+            };
+
+            // Closes the incoming half of every channel
+            {
+                let mut state = router.state.lock().unwrap();
+                state.issued.clear();
+                state.redeemed.clear();
+            }
+
+            Err::<(), _>(err)
+        });
+    }
+
+    fn dispatch(&self, mut datagram: Bytes) {
+        // Datagrams carry the same header as streams
+        if datagram.len() < STREAM_HEADER_SIZE {
+            return;
+        }
+        let payload = datagram.split_off(STREAM_HEADER_SIZE);
+        let Ok(header) = decode::<StreamHeader>(&datagram) else {
+            return;
+        };
+
+        // A datagram from the issuer is for the redeemer, and vice versa
+        let mut state = self.state.lock().unwrap();
+        if let Some(channel) = state.table(!header.from_issuer).get_mut(&header.id) {
+            // Drop the datagram if the channel is full; it's stale by the time it's read anyway
+            let _ = channel.try_send(payload);
+        }
+    }
+}
+
+/// One end of an unreliable, unordered message channel. Messages are sent with `send()` and
+/// received as a `Stream`. The stream ends when the session closes.
+pub struct DatagramChannel<Tx, Rx> {
+    router: DatagramRouter,
+    header: StreamHeader,
+    incoming: mpsc::Receiver<Bytes>,
+    codec: AnyCodec,
+    _phantom: PhantomData<fn() -> (Tx, Rx)>,
+}
+
+impl<Tx: Serialize, Rx> DatagramChannel<Tx, Rx> {
+    /// Sends a message, which may or may not arrive. Messages larger than `MAX_DATAGRAM_SIZE`
+    /// after encoding are rejected.
+    pub async fn send(&mut self, msg: &Tx) -> Result<(), FrameworkError> {
+        let mut datagram = encode(&self.header)?;
+        datagram.extend_from_slice(&self.codec.encode(msg)?);
+
+        if datagram.len() > MAX_DATAGRAM_SIZE {
+            return Err(FrameworkError::FrameTooLarge {
+                size: datagram.len(),
+                max: MAX_DATAGRAM_SIZE,
+            });
+        }
+
+        self.router.sess.send_datagram(datagram.into()).await
+    }
+}
+
+impl<Tx, Rx: DeserializeOwned> Stream for DatagramChannel<Tx, Rx> {
+    type Item = Result<Rx, FrameworkError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let codec = self.codec;
+        self.incoming
+            .poll_next_unpin(cx)
+            .map(|datagram| datagram.map(|bytes| codec.decode::<Rx>(&bytes)))
+    }
+}
+
+impl<Tx, Rx> Drop for DatagramChannel<Tx, Rx> {
+    fn drop(&mut self) {
+        // Before taking the lock, so that a channel admitted later is seen to be gone
+        self.incoming.close();
+        let mut state = self.router.state.lock().unwrap();
+        state.table(self.header.from_issuer).remove(&self.header.id);
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::codec::{AnyCodec, Codec};
+use crate::io::{decode, encode, read_frame, write_frame, FrameOptions, FrameworkError};
+use crate::schema::fingerprint;
+use crate::session::{AnyRecvStream, AnySendStream};
+use crate::Role;
+
+/// Version of the framework's own wire format. Peers must use the same one.
+pub const WIRE_VERSION: u32 = 6;
+
+/// Largest handshake we accept from the peer
+const MAX_HANDSHAKE_SIZE: usize = 4096;
+
+/// Settings for a new framework, exchanged with the peer before anything else
+#[derive(Clone, Default)]
+pub struct FrameworkConfig {
+    /// Used for the root transport, and for the tokens issued unless overridden. Must be the
+    /// same as the peer's.
+    pub codec: AnyCodec,
+    /// Framing of the root transport, and of the tokens issued unless overridden. Bounds what
+    /// a peer can make us buffer on the root stream, see `FrameOptions::max_frame_size`. The
+    /// compression must be the same as the peer's, e.g. to compress large root responses.
+    pub frames: FrameOptions,
+    /// Name of the root service. Must be the same as the peer's.
+    pub service: String,
+    /// Version of your own protocol, which the peer can look up with `peer_version()`. This
+    /// isn't checked, so that peers can decide for themselves which versions they support.
+    pub version: u32,
+    /// Sent by a client to the server's `Authenticator`, e.g. a token or a password. Never
+    /// sent by the server.
+    pub credentials: Option<String>,
+}
+
+// Keeps the credentials out of logs
+impl std::fmt::Debug for FrameworkConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameworkConfig")
+            .field("codec", &self.codec)
+            .field("frames", &self.frames)
+            .field("service", &self.service)
+            .field("version", &self.version)
+            .field(
+                "credentials",
+                &self.credentials.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+/// Sent by both peers at the start of the root stream
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    // Must stay first, so that it can be read whatever else changes
+    wire_version: u32,
+    version: u32,
+    service: String,
+    codec: String,
+    /// Compression of the root stream
+    compression: String,
+    /// Fingerprints of the types sent and received on the root stream
+    sends: u64,
+    receives: u64,
+    credentials: Option<String>,
+}
+
+/// What the peer told us about itself
+pub(crate) struct PeerHello {
+    pub version: u32,
+    pub credentials: Option<String>,
+}
+
+/// Exchanges `Hello`s on the root stream, failing with `FrameworkError::ProtocolMismatch` if
+/// the peers can't understand each other. Without this, a mismatch would only show up as
+/// confusing decode errors later on.
+pub(crate) async fn handshake<Rx: DeserializeOwned + 'static, Tx: DeserializeOwned + 'static>(
+    (tx, rx): &mut (AnySendStream, AnyRecvStream),
+    role: Role,
+    config: &FrameworkConfig,
+) -> Result<PeerHello, FrameworkError> {
+    let ours = Hello {
+        wire_version: WIRE_VERSION,
+        version: config.version,
+        service: config.service.clone(),
+        codec: config.codec.name().into(),
+        compression: config.frames.compression.name().into(),
+        sends: fingerprint::<Tx>(),
+        receives: fingerprint::<Rx>(),
+        // Otherwise a server sharing its config with clients would hand them its credentials
+        credentials: match role {
+            Role::Client => config.credentials.clone(),
+            Role::Server => None,
+        },
+    };
+    write_frame(tx, &encode(&ours)?, MAX_HANDSHAKE_SIZE).await?;
+
+    let Some(frame) = read_frame(rx, MAX_HANDSHAKE_SIZE).await? else {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    };
+    let theirs = match decode::<Hello>(&frame) {
+        Ok(theirs) => theirs,
+        Err(e) => {
+            // A different wire version may well have a different `Hello`
+            let wire_version = frame.get(..4).and_then(|v| decode::<u32>(v).ok());
+            return match wire_version {
+                Some(v) if v != WIRE_VERSION => Err(mismatch("wire version", WIRE_VERSION, v)),
+                _ => Err(e),
+            };
+        }
+    };
+
+    if theirs.wire_version != WIRE_VERSION {
+        return Err(mismatch("wire version", WIRE_VERSION, theirs.wire_version));
+    }
+    if theirs.service != ours.service {
+        return Err(mismatch("service", &ours.service, &theirs.service));
+    }
+    if theirs.codec != ours.codec {
+        return Err(mismatch("codec", &ours.codec, &theirs.codec));
+    }
+    if theirs.compression != ours.compression {
+        return Err(mismatch(
+            "compression",
+            &ours.compression,
+            &theirs.compression,
+        ));
+    }
+    if (theirs.receives, theirs.sends) != (ours.sends, ours.receives) {
+        return Err(FrameworkError::ProtocolMismatch {
+            expected: format!("root messages {:016x}/{:016x}", ours.sends, ours.receives),
+            found: format!(
+                "root messages {:016x}/{:016x}",
+                theirs.receives, theirs.sends
+            ),
+        });
+    }
+
+    Ok(PeerHello {
+        version: theirs.version,
+        credentials: theirs.credentials,
+    })
+}
+
+fn mismatch(
+    what: &str,
+    ours: impl std::fmt::Debug,
+    theirs: impl std::fmt::Debug,
+) -> FrameworkError {
+    FrameworkError::ProtocolMismatch {
+        expected: format!("{what} {ours:?}"),
+        found: format!("{what} {theirs:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{MemorySession, Session};
+
+    type Streams = (AnySendStream, AnyRecvStream);
+
+    /// Both ends of a bidirectional stream, along with the sessions they belong to
+    async fn bi_stream() -> (Streams, Streams, impl Sized) {
+        let (a, b) = MemorySession::pair();
+        let (opened, accepted) = tokio::join!(a.open_bi(), b.accept_bi());
+        let ((a_tx, a_rx), (b_tx, b_rx)) = (opened.unwrap(), accepted.unwrap());
+        (
+            (a_tx.into(), a_rx.into()),
+            (b_tx.into(), b_rx.into()),
+            (a, b),
+        )
+    }
+
+    fn mismatched(result: Result<PeerHello, FrameworkError>, what: &str) -> bool {
+        matches!(result, Err(FrameworkError::ProtocolMismatch { expected, .. }) if expected.starts_with(what))
+    }
+
+    #[tokio::test]
+    async fn matching_peers_shake_hands() {
+        let (mut client, mut server, _sessions) = bi_stream().await;
+        let config = FrameworkConfig {
+            version: 3,
+            ..Default::default()
+        };
+
+        let (client, server) = tokio::join!(
+            handshake::<u32, String>(&mut client, Role::Client, &config),
+            handshake::<String, u32>(&mut server, Role::Server, &config),
+        );
+        assert_eq!(client.unwrap().version, 3);
+        assert_eq!(server.unwrap().version, 3);
+    }
+
+    #[tokio::test]
+    async fn different_root_messages_are_rejected() {
+        let (mut client, mut server, _sessions) = bi_stream().await;
+        let config = FrameworkConfig::default();
+
+        let (client, server) = tokio::join!(
+            handshake::<u32, String>(&mut client, Role::Client, &config),
+            handshake::<String, u64>(&mut server, Role::Server, &config),
+        );
+        assert!(mismatched(client, "root messages"));
+        assert!(mismatched(server, "root messages"));
+    }
+
+    #[tokio::test]
+    async fn other_wire_versions_are_rejected() {
+        let (mut ours, (mut tx, _rx), _sessions) = bi_stream().await;
+        let config = FrameworkConfig::default();
+
+        // A peer whose `Hello` has the same layout
+        let theirs = Hello {
+            wire_version: WIRE_VERSION + 1,
+            version: 0,
+            service: String::new(),
+            codec: config.codec.name().into(),
+            compression: config.frames.compression.name().into(),
+            sends: fingerprint::<String>(),
+            receives: fingerprint::<u32>(),
+            credentials: None,
+        };
+        write_frame(&mut tx, &encode(&theirs).unwrap(), MAX_HANDSHAKE_SIZE)
+            .await
+            .unwrap();
+        let result = handshake::<u32, String>(&mut ours, Role::Client, &config).await;
+        assert!(mismatched(result, "wire version"));
+    }
+
+    #[tokio::test]
+    async fn wire_versions_with_another_hello_are_rejected() {
+        let (mut ours, (mut tx, _rx), _sessions) = bi_stream().await;
+
+        // Nothing but the version, which is all an older or newer peer has in common with us
+        let theirs = encode(&(WIRE_VERSION - 1)).unwrap();
+        write_frame(&mut tx, &theirs, MAX_HANDSHAKE_SIZE)
+            .await
+            .unwrap();
+        let result =
+            handshake::<u32, String>(&mut ours, Role::Client, &FrameworkConfig::default()).await;
+        assert!(mismatched(result, "wire version"));
+    }
+}
@@ -1,110 +1,760 @@
-use bincode::config::{Fixint, LittleEndian, NoLimit};
-use bytes::Bytes;
-//use polyfill_tokio_mem::DuplexStream;
-use serde::{de::DeserializeOwned, Serialize};
+use bincode::config::{Configuration, Fixint, LittleEndian};
+use bytes::{Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tarpc::Transport;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
-use tokio_util::codec::{Decoder, LengthDelimitedCodec};
 
-use futures::{SinkExt, StreamExt};
-use web_transport::{RecvStream, SendStream};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-/*
-/// Internal type representing the identity of a connection between client and server
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-struct BiStream(usize);
-*/
+use futures::{Sink, Stream};
 
-const BUFFER_SIZE: usize = 4096; // Chosen arbitrarily!
-const MAX_READ_BYTES: usize = 4096; // Chosen arbitrarily!
+use crate::codec::Codec;
+use crate::compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::router::StreamHeader;
+use crate::session::{AnyRecvStream, AnySendStream, RecvStream, SendStream};
+use crate::stats::{timed, StreamCounter};
 
-/*
-/// This is the type used to provide connectivity to an alternate tarpc connection
-#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
-pub struct TarpcBiStream(BiStream);
-*/
+/// Capacity of the in-memory pipe behind a raw stream's `DuplexStream`, which is also the most
+/// the bridge writes to the stream at once. A full pipe holds back whoever writes into it, so
+/// this bounds what a raw stream buffers on our side; raw streams have no frames to size it by.
+const BUFFER_SIZE: usize = 4096;
 
-/// Converts a webtransport bidirectional connection into a DuplexStream
+/// Most the bridge reads from the stream at once, so that each read fits in the pipe whole
+const MAX_READ_BYTES: usize = BUFFER_SIZE;
+
+/// Largest frame accepted unless configured otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// Most memory that decoding one message may claim for its collections and strings unless
+/// configured otherwise, see `FrameOptions::max_decode_allocation`
+pub const DEFAULT_MAX_DECODE_ALLOCATION: usize = 64 * 1024 * 1024;
+
+/// Error code sent to the peer when we stop reading a stream because of an oversize frame
+pub const FRAME_TOO_LARGE_CODE: u32 = 0x46;
+
+/// Error code sent to the peer when we stop reading a stream because it uses different framing
+pub const PROTOCOL_MISMATCH_CODE: u32 = 0x50;
+
+/// Error code sent to the peer when it opens the stream of a token whose RPC call was cancelled
+pub const CALL_CANCELLED_CODE: u32 = 0x43;
+
+/// Error code sent to the peer when we stop a stream it opened ahead of our redeeming the
+/// token, because too many such streams are held already or the token wasn't redeemed in
+/// time, or when it doesn't send a stream's header in time
+pub const STREAM_REFUSED_CODE: u32 = 0x48;
+
+/// How messages are framed on a stream. Both ends of the stream must agree on these.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct FrameOptions {
+    /// Larger encoded messages fail with `FrameworkError::FrameTooLarge`, both before and after
+    /// compression. The flag in front of each frame on a compressed stream doesn't count.
+    pub max_frame_size: usize,
+    /// Most memory that decoding one message may claim for its collections and strings,
+    /// whatever their length prefixes say. Messages which need more fail with
+    /// `FrameworkError::Decode`.
+    pub max_decode_allocation: usize,
+    pub compression: Compression,
+    /// Frames smaller than this are sent uncompressed
+    pub compression_threshold: usize,
+}
+
+impl FrameOptions {
+    /// Largest frame on the wire, which is a message of `max_frame_size` plus its flag
+    fn max_wire_size(&self) -> usize {
+        self.max_frame_size
+            .saturating_add(self.compression.frame_overhead())
+    }
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_decode_allocation: DEFAULT_MAX_DECODE_ALLOCATION,
+            compression: Compression::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+/// Converts a webtransport bidirectional connection into a DuplexStream, for raw byte streams.
+/// Framed transports use `webtransport_protocol()` instead, which doesn't need this.
 /// Warning: spawns tasks underneath
-pub fn webtransport_futures_bridge((mut tx, mut rx): (SendStream, RecvStream)) -> DuplexStream {
+pub fn webtransport_futures_bridge(
+    (tx, rx): (impl Into<AnySendStream>, impl Into<AnyRecvStream>),
+) -> DuplexStream {
+    let (mut tx, mut rx): (AnySendStream, AnyRecvStream) = (tx.into(), rx.into());
     let (proxy, ret) = tokio::io::duplex(BUFFER_SIZE);
 
     let (mut readhalf, mut writehalf) = tokio::io::split(proxy);
 
     crate::spawn(async move {
+        let mut buf = vec![0_u8; BUFFER_SIZE];
         loop {
-            let mut buf = vec![0_u8; BUFFER_SIZE];
-
             let n_bytes_read = readhalf.read(&mut buf).await?;
-            buf.truncate(n_bytes_read);
+            if n_bytes_read == 0 {
+                // The duplex was shut down; dropping tx ends the stream for the peer
+                break;
+            }
 
-            tx.write(&buf).await?;
+            write_all(&mut tx, &buf[..n_bytes_read]).await?;
         }
 
-        #[allow(unreachable_code)]
         Ok::<_, FrameworkError>(())
     });
 
     crate::spawn(async move {
-        while let Some(bytes) = rx.read(MAX_READ_BYTES).await? {
-            writehalf.write_all(bytes.as_ref()).await?;
+        let result = async {
+            while let Some(bytes) = rx.read(MAX_READ_BYTES).await? {
+                writehalf.write_all(bytes.as_ref()).await?;
+            }
+            Ok::<_, FrameworkError>(())
         }
+        .await;
 
-        #[allow(unreachable_code)]
-        Ok::<_, FrameworkError>(())
+        // Let the reader see the end of the stream, even if the session died underneath
+        let _ = writehalf.shutdown().await;
+
+        result
     });
 
     ret
 }
 
-pub fn webtransport_protocol<Rx: DeserializeOwned, Tx: Serialize>(
-    socks: (SendStream, RecvStream),
+/// Typed, length-prefixed messages over a bidirectional stream. Works directly on the stream,
+/// without spawning anything. An oversize or mismatched incoming frame stops just this stream.
+pub fn webtransport_protocol<Rx: DeserializeOwned, Tx: Serialize, C: Codec>(
+    (tx, rx): (impl Into<AnySendStream>, impl Into<AnyRecvStream>),
+    codec: C,
+    frames: FrameOptions,
 ) -> impl Transport<Tx, Rx, Error = FrameworkError> {
-    let duplex = webtransport_futures_bridge(socks);
+    counted_protocol((tx, rx), codec, frames, StreamCounter::default())
+}
 
-    LengthDelimitedCodec::default()
-        .framed(duplex)
-        .sink_map_err(FrameworkError::from)
-        .with(|obj: Tx| async move { Ok(Bytes::from(encode(&obj)?)) })
-        .map(|frame| Ok(decode(&frame?)?))
+/// `webtransport_protocol()`, counting the traffic of both halves
+pub(crate) fn counted_protocol<Rx: DeserializeOwned, Tx: Serialize, C: Codec>(
+    (tx, rx): (impl Into<AnySendStream>, impl Into<AnyRecvStream>),
+    codec: C,
+    frames: FrameOptions,
+    counter: StreamCounter,
+) -> impl Transport<Tx, Rx, Error = FrameworkError> {
+    SplitTransport {
+        sink: counted_sender(tx, codec.clone(), frames, counter.clone()),
+        stream: counted_receiver(rx, codec, frames, counter),
+    }
+}
+
+/// A transport made of separate sending and receiving halves
+pub(crate) struct SplitTransport<Si, St> {
+    pub sink: Si,
+    pub stream: St,
+}
+
+impl<T, Si: Sink<T> + Unpin, St: Unpin> Sink<T> for SplitTransport<Si, St> {
+    type Error = Si::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Si::Error> {
+        Pin::new(&mut self.sink).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        Pin::new(&mut self.sink).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        Pin::new(&mut self.sink).poll_close(cx)
+    }
+}
+
+impl<Si: Unpin, St: Stream + Unpin> Stream for SplitTransport<Si, St> {
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<St::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum FrameworkError {
-    #[error("Derialization")]
-    BinDecode(#[from] bincode::error::DecodeError),
+    #[error("Failed to decode {type_name}: {source}")]
+    Decode {
+        type_name: &'static str,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
 
-    #[error("Serialization")]
-    BinEncode(#[from] bincode::error::EncodeError),
+    #[error("Failed to encode {type_name}: {source}")]
+    Encode {
+        type_name: &'static str,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
 
-    #[error("Websocket error {0}")]
-    WebSocket(String),
+    #[error("WebTransport error: {0}")]
+    Transport(String),
 
-    #[error("Duplex IO")]
+    #[error("Duplex IO: {0}")]
     Io(#[from] std::io::Error),
+
+    #[error("The session ended before the peer connected the stream")]
+    StreamNotConnected,
+
+    #[error("The peer did not connect the stream within {0:?}")]
+    ConnectTimeout(std::time::Duration),
+
+    #[error("The deadline of the call which issued the token passed before the peer connected")]
+    DeadlineExceeded,
+
+    #[error("The call which issued the token was cancelled")]
+    Cancelled,
+
+    #[error("The server rejected our credentials: {0}")]
+    Unauthorized(String),
+
+    #[error("The peer discarded the token without connecting it")]
+    TokenDiscarded,
+
+    #[error("Session closed with code {code}: {reason}")]
+    Closed { code: u32, reason: String },
+
+    #[error("Stream reset by the peer with code {code}")]
+    StreamReset { code: u32 },
+
+    #[error("Frame of {size} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge { size: usize, max: usize },
+
+    #[error("Protocol mismatch: expected {expected}, peer has {found}")]
+    ProtocolMismatch { expected: String, found: String },
+
+    #[error("Failed to decompress a frame: {0}")]
+    Decompress(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("The stream ended before the issuer completed it")]
+    StreamIncomplete,
+
+    #[error("The token's capability was rejected: {0}")]
+    CapabilityRejected(String),
+}
+
+/// What the caller can do about a `FrameworkError`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// The session is fine, trying again (e.g. with a new token) may work
+    Retry,
+    /// The session is gone, reconnect before trying again
+    Reconnect,
+    /// Trying again will fail the same way, so report it
+    Report,
+}
+
+impl FrameworkError {
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Self::Io(_)
+            | Self::ConnectTimeout(_)
+            | Self::DeadlineExceeded
+            | Self::Cancelled
+            | Self::StreamReset { .. }
+            | Self::StreamIncomplete => Recovery::Retry,
+            Self::Transport(_) | Self::StreamNotConnected | Self::Closed { .. } => {
+                Recovery::Reconnect
+            }
+            Self::Decode { .. }
+            | Self::Encode { .. }
+            | Self::TokenDiscarded
+            | Self::FrameTooLarge { .. }
+            | Self::ProtocolMismatch { .. }
+            | Self::Unauthorized(_)
+            | Self::Decompress(_)
+            | Self::CapabilityRejected(_) => Recovery::Report,
+        }
+    }
+
+    /// Whether the operation may succeed if tried again on the same session
+    pub fn is_retryable(&self) -> bool {
+        self.recovery() == Recovery::Retry
+    }
 }
 
 impl From<web_transport::Error> for FrameworkError {
+    #[cfg(not(target_arch = "wasm32"))]
     fn from(value: web_transport::Error) -> Self {
-        Self::WebSocket(value.to_string())
+        use web_transport_quinn::{ReadError, WriteError};
+
+        match value {
+            web_transport::Error::Read(ReadError::Reset(code))
+            | web_transport::Error::Write(WriteError::Stopped(code)) => Self::StreamReset { code },
+            web_transport::Error::Session(e)
+            | web_transport::Error::Read(ReadError::SessionError(e))
+            | web_transport::Error::Write(WriteError::SessionError(e)) => e.into(),
+            other => Self::Transport(other.to_string()),
+        }
+    }
+
+    /// The browser only reports the code of a reset stream in the message of its
+    /// `WebTransportError`, which the error's message carries along
+    #[cfg(target_arch = "wasm32")]
+    fn from(value: web_transport::Error) -> Self {
+        let msg = value.to_string();
+        match reset_code(&msg, "RESET_STREAM: ").or_else(|| reset_code(&msg, "STOP_SENDING: ")) {
+            Some(code) => Self::StreamReset { code },
+            None => Self::Transport(msg),
+        }
+    }
+}
+
+/// How the session ended, which is the same whichever operation noticed it
+#[cfg(not(target_arch = "wasm32"))]
+impl From<web_transport_quinn::SessionError> for FrameworkError {
+    fn from(value: web_transport_quinn::SessionError) -> Self {
+        use web_transport_quinn::{SessionError, WebTransportError};
+
+        match value {
+            SessionError::WebTransportError(WebTransportError::Closed(code, reason)) => {
+                Self::Closed { code, reason }
+            }
+            SessionError::ConnectionError(quinn::ConnectionError::ApplicationClosed(close)) => {
+                let reason = String::from_utf8_lossy(&close.reason).into_owned();
+                match webtransport_code(close.error_code.into_inner()) {
+                    Some(code) => Self::Closed { code, reason },
+                    None => Self::Transport(format!(
+                        "Connection closed with code {}: {reason}",
+                        close.error_code
+                    )),
+                }
+            }
+            other => Self::Transport(other.to_string()),
+        }
+    }
+}
+
+/// First HTTP/3 error code which carries a WebTransport application error code
+#[cfg(not(target_arch = "wasm32"))]
+const WEBTRANSPORT_CODE_BASE: u64 = 0x52e4a40fa8db;
+
+/// The application error code a peer closed the session with, from the HTTP/3 error code it
+/// is carried in. Every 0x1f-th code is reserved, and carries none.
+#[cfg(not(target_arch = "wasm32"))]
+fn webtransport_code(http3: u64) -> Option<u32> {
+    let shifted = http3.checked_sub(WEBTRANSPORT_CODE_BASE)?;
+    if shifted % 0x1f == 0x1e {
+        return None;
+    }
+    u32::try_from(shifted - shifted / 0x1f).ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn reset_code(msg: &str, prefix: &str) -> Option<u32> {
+    let (_, rest) = msg.split_once(prefix)?;
+    let digits = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+    digits.parse().ok()
+}
+
+/// Size of the encoded `StreamHeader`
+pub(crate) const STREAM_HEADER_SIZE: usize = std::mem::size_of::<u64>() + 1;
+
+/// Size of the big endian length prefix on each frame
+const FRAME_LENGTH_SIZE: usize = std::mem::size_of::<u32>();
+
+fn check_frame_size(size: usize, max: usize) -> Result<(), FrameworkError> {
+    if size > max {
+        return Err(FrameworkError::FrameTooLarge { size, max });
+    }
+    Ok(())
+}
+
+fn frame_length(payload: &[u8]) -> Result<u32, FrameworkError> {
+    u32::try_from(payload.len()).map_err(|_| FrameworkError::FrameTooLarge {
+        size: payload.len(),
+        max: u32::MAX as usize,
+    })
+}
+
+/// Writes the entire buffer to the stream
+pub(crate) async fn write_all(
+    tx: &mut AnySendStream,
+    mut buf: &[u8],
+) -> Result<(), FrameworkError> {
+    while !buf.is_empty() {
+        let n = tx.write(buf).await?;
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+/// Reads exactly `len` bytes from the stream
+pub(crate) async fn read_exact(
+    rx: &mut AnyRecvStream,
+    len: usize,
+) -> Result<Bytes, FrameworkError> {
+    let mut buf = BytesMut::new();
+    while buf.len() < len {
+        let Some(bytes) = rx.read(len - buf.len()).await? else {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        };
+
+        // Usually everything arrives in one chunk, which is handed out without copying
+        if bytes.len() == len {
+            return Ok(bytes);
+        }
+
+        buf.reserve(len - buf.len());
+        buf.extend_from_slice(&bytes);
+    }
+    Ok(buf.freeze())
+}
+
+/// Reads until the peer finishes the stream, up to `limit` bytes
+pub(crate) async fn read_to_end(
+    rx: &mut AnyRecvStream,
+    limit: usize,
+) -> Result<Vec<u8>, FrameworkError> {
+    let mut buf = vec![];
+    while let Some(bytes) = rx.read(limit - buf.len()).await? {
+        buf.extend_from_slice(&bytes);
+        if buf.len() >= limit {
+            break;
+        }
     }
+    Ok(buf)
+}
+
+/// Identifies a newly opened stream to the peer
+pub(crate) async fn write_stream_header(
+    tx: &mut AnySendStream,
+    header: StreamHeader,
+) -> Result<(), FrameworkError> {
+    write_all(tx, &encode(&header)?).await
+}
+
+/// Reads the identity of a newly accepted stream
+pub(crate) async fn read_stream_header(
+    rx: &mut AnyRecvStream,
+) -> Result<StreamHeader, FrameworkError> {
+    Ok(decode(&read_exact(rx, STREAM_HEADER_SIZE).await?)?)
+}
+
+/// Writes a length-prefixed frame
+pub(crate) async fn write_frame(
+    tx: &mut AnySendStream,
+    payload: &[u8],
+    max_frame_size: usize,
+) -> Result<(), FrameworkError> {
+    check_frame_size(payload.len(), max_frame_size)?;
+    let len = frame_length(payload)?;
+
+    write_all(tx, &len.to_be_bytes()).await?;
+    write_all(tx, payload).await
+}
+
+/// Reads a frame written by `write_frame()`. Returns `None` if the stream ends between frames.
+/// An oversize frame stops the stream, so the peer's writes fail instead of filling our buffers.
+pub(crate) async fn read_frame(
+    rx: &mut AnyRecvStream,
+    max_frame_size: usize,
+) -> Result<Option<Bytes>, FrameworkError> {
+    let Some(first) = rx.read(FRAME_LENGTH_SIZE).await? else {
+        return Ok(None);
+    };
+    let mut len = [0; FRAME_LENGTH_SIZE];
+    len[..first.len()].copy_from_slice(&first);
+    len[first.len()..].copy_from_slice(&read_exact(rx, FRAME_LENGTH_SIZE - first.len()).await?);
+    let len = u32::from_be_bytes(len) as usize;
+
+    if let Err(e) = check_frame_size(len, max_frame_size) {
+        rx.stop(FRAME_TOO_LARGE_CODE);
+        return Err(e);
+    }
+
+    Ok(Some(read_exact(rx, len).await?))
+}
+
+/// Tells the receiver how the frames which follow are compressed
+async fn write_preamble(
+    tx: &mut AnySendStream,
+    frames: &FrameOptions,
+) -> Result<(), FrameworkError> {
+    write_all(tx, &[frames.compression.id()]).await
+}
+
+/// Checks that the sender compresses frames the way we expect. Returns `false` if the stream
+/// ended before any frames were sent.
+async fn read_preamble(
+    rx: &mut AnyRecvStream,
+    frames: &FrameOptions,
+) -> Result<bool, FrameworkError> {
+    let Some(id) = rx.read(1).await? else {
+        return Ok(false);
+    };
+
+    if id[0] != frames.compression.id() {
+        rx.stop(PROTOCOL_MISMATCH_CODE);
+        let found = Compression::from_id(id[0]).map_or("unknown", |c| c.name());
+        return Err(FrameworkError::ProtocolMismatch {
+            expected: format!("compression {}", frames.compression.name()),
+            found: format!("compression {found}"),
+        });
+    }
+
+    Ok(true)
+}
+
+/// Typed sending end of a unidirectional stream. The receiver sees the end of the stream once
+/// this is dropped.
+pub fn webtransport_sender<Tx: Serialize, C: Codec>(
+    tx: impl Into<AnySendStream>,
+    codec: C,
+    frames: FrameOptions,
+) -> impl Sink<Tx, Error = FrameworkError> + Unpin {
+    counted_sender(tx, codec, frames, StreamCounter::default())
+}
+
+/// `webtransport_sender()`, counting what is sent and the errors
+pub(crate) fn counted_sender<Tx: Serialize, C: Codec>(
+    tx: impl Into<AnySendStream>,
+    codec: C,
+    frames: FrameOptions,
+    counter: StreamCounter,
+) -> impl Sink<Tx, Error = FrameworkError> + Unpin {
+    let tx: AnySendStream = tx.into();
+    Box::pin(futures::sink::unfold(
+        (tx, false),
+        move |(mut tx, started), obj: Tx| {
+            let codec = codec.clone();
+            let counter = counter.clone();
+            async move {
+                let result = async {
+                    if !started {
+                        write_preamble(&mut tx, &frames).await?;
+                    }
+
+                    let (frame, encode_time) = timed(|| -> Result<_, FrameworkError> {
+                        let payload = codec.encode(&obj)?;
+                        check_frame_size(payload.len(), frames.max_frame_size)?;
+                        Ok(frames
+                            .compression
+                            .compress(payload, frames.compression_threshold))
+                    });
+                    let frame = frame?;
+                    write_frame(&mut tx, &frame, frames.max_wire_size()).await?;
+
+                    counter.sent(FRAME_LENGTH_SIZE + frame.len(), encode_time);
+                    Ok::<_, FrameworkError>(())
+                }
+                .await;
+
+                if result.is_err() {
+                    counter.error();
+                }
+                result.map(|()| (tx, true))
+            }
+        },
+    ))
+}
+
+/// Typed receiving end of a unidirectional stream. Ends when the sender is dropped.
+pub fn webtransport_receiver<Rx: DeserializeOwned, C: Codec>(
+    rx: impl Into<AnyRecvStream>,
+    codec: C,
+    frames: FrameOptions,
+) -> impl Stream<Item = Result<Rx, FrameworkError>> + Unpin {
+    counted_receiver(rx, codec, frames, StreamCounter::default())
+}
+
+/// `webtransport_receiver()`, counting what is received and the errors
+pub(crate) fn counted_receiver<Rx: DeserializeOwned, C: Codec>(
+    rx: impl Into<AnyRecvStream>,
+    codec: C,
+    frames: FrameOptions,
+    counter: StreamCounter,
+) -> impl Stream<Item = Result<Rx, FrameworkError>> + Unpin {
+    let rx: AnyRecvStream = rx.into();
+    Box::pin(futures::stream::try_unfold(
+        (rx, false),
+        move |(mut rx, started)| {
+            let codec = codec.clone();
+            let counter = counter.clone();
+            async move {
+                let result = async {
+                    if !started && !read_preamble(&mut rx, &frames).await? {
+                        return Ok::<_, FrameworkError>(None);
+                    }
+
+                    let Some(frame) = read_frame(&mut rx, frames.max_wire_size()).await? else {
+                        return Ok(None);
+                    };
+                    let size = FRAME_LENGTH_SIZE + frame.len();
+                    let (obj, decode_time) = timed(|| -> Result<Rx, FrameworkError> {
+                        let payload = frames
+                            .compression
+                            .decompress(frame, frames.max_frame_size)?;
+                        codec.decode_limited::<Rx>(&payload, frames.max_decode_allocation)
+                    });
+                    let obj = obj?;
+
+                    counter.received(size, decode_time);
+                    Ok(Some(obj))
+                }
+                .await;
+
+                if result.is_err() {
+                    counter.error();
+                }
+                Ok(result?.map(|obj| (obj, (rx, true))))
+            }
+        },
+    ))
 }
 
 /// The encoding function for all data. Mostly for internal use, exposed here for debugging
 /// potential
-pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, bincode::error::EncodeError> {
-    //serde_json::to_writer_pretty(std::io::stdout(), value).unwrap();
-    bincode::serde::encode_to_vec(value, config())
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, FrameworkError> {
+    bincode::serde::encode_to_vec(value, config()).map_err(|source| FrameworkError::Encode {
+        type_name: std::any::type_name::<T>(),
+        source: source.into(),
+    })
 }
 
 /// The dencoding function for all data. Mostly for internal use, exposed here for debugging
 /// potential
-pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, bincode::error::DecodeError> {
-    Ok(bincode::serde::decode_from_slice(bytes, config())?.0)
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, FrameworkError> {
+    crate::codec::Bincode.decode(bytes)
 }
 
-fn config() -> bincode::config::Configuration<LittleEndian, Fixint, NoLimit> {
+/// Bincode with fixed size little endian integers. Decoding is bounded by `crate::limit`
+/// instead of a bincode limit, so that the bound can be configured.
+pub(crate) fn config() -> Configuration<LittleEndian, Fixint> {
     bincode::config::standard()
         .with_little_endian()
         .with_fixed_int_encoding()
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use web_transport_quinn::{ReadError, SessionError, WebTransportError, WriteError};
+
+    use super::*;
+    use crate::codec::Bincode;
+    use crate::session::{MemorySession, Session};
+
+    /// Both ends of a unidirectional stream, along with the sessions they belong to
+    async fn uni_stream() -> (AnySendStream, AnyRecvStream, impl Sized) {
+        let (a, b) = MemorySession::pair();
+        let (tx, rx) = tokio::join!(a.open_uni(), b.accept_uni());
+        (tx.unwrap().into(), rx.unwrap().into(), (a, b))
+    }
+
+    /// Bytes which don't compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn messages_of_the_largest_size_go_through() {
+        for compression in [
+            Compression::None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ] {
+            // The message is a length followed by the bytes
+            let frames = FrameOptions {
+                max_frame_size: 8 + 100,
+                compression,
+                compression_threshold: 0,
+                ..FrameOptions::default()
+            };
+            let (tx, rx, _sessions) = uni_stream().await;
+            let mut tx = webtransport_sender::<Vec<u8>, _>(tx, Bincode, frames);
+            let mut rx = webtransport_receiver::<Vec<u8>, _>(rx, Bincode, frames);
+
+            tx.send(noise(100)).await.unwrap();
+            assert_eq!(rx.next().await.unwrap().unwrap(), noise(100));
+            assert!(matches!(
+                tx.send(noise(101)).await,
+                Err(FrameworkError::FrameTooLarge {
+                    size: 109,
+                    max: 108
+                })
+            ));
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn compressed_messages_round_trip() {
+        let frames = FrameOptions {
+            compression: Compression::Lz4,
+            ..FrameOptions::default()
+        };
+        let (tx, rx, _sessions) = uni_stream().await;
+        let mut tx = webtransport_sender::<Vec<u8>, _>(tx, Bincode, frames);
+        let mut rx = webtransport_receiver::<Vec<u8>, _>(rx, Bincode, frames);
+
+        for msg in [vec![7; 10_000], noise(10_000), vec![1, 2, 3]] {
+            tx.send(msg.clone()).await.unwrap();
+            assert_eq!(rx.next().await.unwrap().unwrap(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn mismatched_compression_is_rejected() {
+        let (mut tx, rx, _sessions) = uni_stream().await;
+        let mut rx = webtransport_receiver::<Vec<u8>, _>(rx, Bincode, FrameOptions::default());
+
+        // An unknown compression id, then an empty frame
+        write_all(&mut tx, &[9, 0, 0, 0, 0]).await.unwrap();
+        assert!(matches!(
+            rx.next().await,
+            Some(Err(FrameworkError::ProtocolMismatch { .. }))
+        ));
+    }
+
+    #[test]
+    fn closed_sessions_keep_their_code_and_reason() {
+        let closed = || SessionError::WebTransportError(WebTransportError::Closed(7, "bye".into()));
+
+        for error in [
+            web_transport::Error::Session(closed()),
+            web_transport::Error::Read(ReadError::SessionError(closed())),
+            web_transport::Error::Write(WriteError::SessionError(closed())),
+        ] {
+            let error = FrameworkError::from(error);
+            assert!(
+                matches!(&error, FrameworkError::Closed { code: 7, reason } if reason == "bye")
+            );
+            assert_eq!(error.recovery(), Recovery::Reconnect);
+        }
+    }
+
+    #[test]
+    fn reset_streams_are_retryable() {
+        let error = FrameworkError::from(web_transport::Error::Read(ReadError::Reset(3)));
+        assert!(matches!(error, FrameworkError::StreamReset { code: 3 }));
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn webtransport_codes_round_trip_through_http3() {
+        for code in [0, 1, 0x1d, 0x1e, 0x1f, 1000, u32::MAX] {
+            let code = u64::from(code);
+            let http3 = WEBTRANSPORT_CODE_BASE + code + code / 0x1e;
+            assert_eq!(webtransport_code(http3), Some(code as u32));
+        }
+        assert_eq!(webtransport_code(WEBTRANSPORT_CODE_BASE + 0x1e), None);
+        assert_eq!(webtransport_code(0x10c), None);
+    }
+}
@@ -1,17 +1,53 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{marker::PhantomData, time::Duration};
 
+use compression::Compression;
 pub use futures;
 use futures::Future;
 use io::FrameworkError;
+#[doc(hidden)]
+pub use paste;
 pub use serde;
 use serde::{de::DeserializeOwned, Serialize};
 pub use tarpc;
 
+use session::AnySession;
 use tarpc::Transport;
-use web_transport::Session;
 
+mod auth;
+mod capability;
+pub mod codec;
+pub mod compression;
+mod datagram;
+mod handshake;
 pub mod io;
+mod limit;
+mod peer;
+mod peer_info;
+mod reconnect;
+mod result_stream;
+mod router;
+mod schema;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+pub mod session;
+pub mod stats;
 mod sync_bistream;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod webtransport;
+pub use auth::{Authenticator, Identity, AUTH_FAILED_CODE};
+pub use capability::{Capability, CapabilityKey, CAPABILITY_REJECTED_CODE};
+use codec::AnyCodec;
+pub use datagram::{DatagramChannel, MAX_DATAGRAM_SIZE};
+pub use handshake::{FrameworkConfig, WIRE_VERSION};
+use peer::StreamOptions;
+pub use peer::{Framework, Role};
+pub use peer_info::PeerInfo;
+pub use reconnect::{Connected, ConnectionState, ReconnectOptions, ReconnectingClient};
+pub use result_stream::ResultStreamError;
+use router::StreamId;
+pub use router::DEFAULT_ACCEPT_TIMEOUT;
 pub use sync_bistream::BiStreamProxy;
 
 #[cfg(target_arch = "wasm32")]
@@ -33,29 +69,206 @@ where
     tokio::spawn(fut);
 }
 
+/// `Send`, except in the browser where nothing needs to be
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSend: Send {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send> MaybeSend for T {}
+
+/// `Send`, except in the browser where nothing needs to be
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSend {}
+#[cfg(target_arch = "wasm32")]
+impl<T> MaybeSend for T {}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(duration: Duration) {
+    futures_timer::Delay::new(duration).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+/// Ties a tarpc client stub to the request and response types of its service, so that
+/// transports for `Subservice` and `OfferedService` tokens have the right types. Implement it
+/// with `impl_service_client!`.
+pub trait ServiceClient {
+    type Req: Serialize + DeserializeOwned;
+    type Resp: Serialize + DeserializeOwned;
+}
+
+/// Implements `ServiceClient` for the client generated by `#[tarpc::service]`, given the name
+/// of the service, e.g. `impl_service_client!(MyService);` ties `MyServiceClient` to
+/// `MyServiceRequest` and `MyServiceResponse`
+#[macro_export]
+macro_rules! impl_service_client {
+    ($service:ident) => {
+        $crate::paste::paste! {
+            impl $crate::ServiceClient for [<$service Client>] {
+                type Req = [<$service Request>];
+                type Resp = [<$service Response>];
+            }
+        }
+    };
+}
+
+/// Tokens which the holder redeems by opening a bidirectional stream. These can be handed to
+/// `discard()` if they won't be used, so that the issuer doesn't wait on them until it times out.
+/// Like all tokens, they carry a `Capability` if the issuer's handle came from
+/// `using_capability()`, which `discard()` presents too.
+///
+/// Simply dropping a token isn't noticed: tokens are plain data, which may be deserialized and
+/// dropped anywhere, so nothing tells the session about it. The issuer's accept future then
+/// only fails once the accept timeout passes, see `Framework::set_accept_timeout()`.
+pub trait StreamToken {
+    #[doc(hidden)]
+    fn stream_id(&self) -> StreamId;
+    #[doc(hidden)]
+    fn capability(&self) -> Option<&Capability>;
+}
+
+/// A two-way stream of messages between the issuer and the holder of the token.
+/// Pass it to `discard()` if it won't be connected, see `StreamToken`.
 // NOTE: Doesn't implement Clone, since we want to this to be consumed
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct BiStream<Rx, Tx> {
+    id: StreamId,
+    options: StreamOptions,
+    capability: Option<Capability>,
     _phantom: PhantomData<(Rx, Tx)>,
 }
 
+/// A one-way feed of messages from the issuer to the holder of the token
+// NOTE: Doesn't implement Clone, since we want to this to be consumed
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Downstream<T> {
+    id: StreamId,
+    options: StreamOptions,
+    capability: Option<Capability>,
+    _phantom: PhantomData<T>,
+}
+
+/// A one-way feed of messages from the holder of the token to the issuer. Dropping it unused is
+/// only noticed by the issuer's accept timeout.
+// NOTE: Doesn't implement Clone, since we want to this to be consumed
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Upstream<T> {
+    id: StreamId,
+    options: StreamOptions,
+    capability: Option<Capability>,
+    _phantom: PhantomData<T>,
+}
+
+/// A finite feed of results from the issuer to the holder of the token, which ends either
+/// with the issuer's first error or with an explicit completion.
+/// Pass it to `discard()` if it won't be connected, see `StreamToken`.
+// NOTE: Doesn't implement Clone, since we want to this to be consumed
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ResultStream<T, E> {
+    id: StreamId,
+    options: StreamOptions,
+    capability: Option<Capability>,
+    _phantom: PhantomData<(T, E)>,
+}
+
+/// Raw bytes, without any framing or encoding. Useful for files, archives and such.
+/// Pass it to `discard()` if it won't be connected, see `StreamToken`.
+// NOTE: Doesn't implement Clone, since we want to this to be consumed
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RawStream {
+    id: StreamId,
+    capability: Option<Capability>,
+}
+
+impl<Rx, Tx> StreamToken for BiStream<Rx, Tx> {
+    fn stream_id(&self) -> StreamId {
+        self.id
+    }
+
+    fn capability(&self) -> Option<&Capability> {
+        self.capability.as_ref()
+    }
+}
+
+impl<Client> StreamToken for Subservice<Client> {
+    fn stream_id(&self) -> StreamId {
+        self.id
+    }
+
+    fn capability(&self) -> Option<&Capability> {
+        self.capability.as_ref()
+    }
+}
+
+impl<Client> StreamToken for OfferedService<Client> {
+    fn stream_id(&self) -> StreamId {
+        self.id
+    }
+
+    fn capability(&self) -> Option<&Capability> {
+        self.capability.as_ref()
+    }
+}
+
+impl<T, E> StreamToken for ResultStream<T, E> {
+    fn stream_id(&self) -> StreamId {
+        self.id
+    }
+
+    fn capability(&self) -> Option<&Capability> {
+        self.capability.as_ref()
+    }
+}
+
+impl StreamToken for RawStream {
+    fn stream_id(&self) -> StreamId {
+        self.id
+    }
+
+    fn capability(&self) -> Option<&Capability> {
+        self.capability.as_ref()
+    }
+}
+
+/// Unreliable messages, for state where a stale value is better dropped than retransmitted
+// NOTE: Doesn't implement Clone, since we want to this to be consumed
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Datagrams<Rx, Tx> {
+    id: StreamId,
+    options: StreamOptions,
+    capability: Option<Capability>,
+    _phantom: PhantomData<(Rx, Tx)>,
+}
+
+/// A service offered by the issuer, which the holder calls.
+/// Pass it to `discard()` if it won't be connected, see `StreamToken`.
 // NOTE: Doesn't implement Clone, since we want to this to be consumed
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Subservice<Client> {
+    id: StreamId,
+    options: StreamOptions,
+    capability: Option<Capability>,
     _phantom: PhantomData<Client>,
 }
 
+/// A service offered by the issuer, like `Subservice`, redeemed with `connect_reverse_service()`.
+/// Pass it to `discard()` if it won't be connected, see `StreamToken`.
 // NOTE: Doesn't implement Clone, since we want to this to be consumed
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct OfferedService<Client> {
+    id: StreamId,
+    options: StreamOptions,
+    capability: Option<Capability>,
     _phantom: PhantomData<Client>,
 }
 
+/// The client end of a session, which opens the root stream. A thin wrapper around `Framework`,
+/// whose methods it has through `Deref`.
 #[derive(Clone)]
 pub struct ClientFramework {
-    // Ensures each open() occurs in sequence with each accept(). We don't open() until the last
-    // one was either completed or failed!
-    pub seq: Arc<futures::lock::Mutex<Session>>,
+    inner: Framework,
 }
 
 /// Don't worry about it
@@ -64,175 +277,897 @@ unsafe impl Send for ClientFramework {}
 
 impl ClientFramework {
     /// Creates a new framework, and offers a root transport
-    pub async fn new<Rx: DeserializeOwned, Tx: Serialize>(
-        mut sess: Session,
+    pub async fn new<Rx: DeserializeOwned + 'static, Tx: Serialize + DeserializeOwned + 'static>(
+        sess: impl Into<AnySession>,
     ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
-        let socks = sess.open_bi().await?;
-        let channel = crate::io::webtransport_protocol(socks);
-        let inst = Self::new_internal(sess);
-        Ok((inst, channel))
+        Self::new_with_config(sess, FrameworkConfig::default()).await
     }
 
-    fn new_internal(sess: Session) -> Self {
+    /// Creates a new framework using the given codec, which the server must also use
+    pub async fn new_with_codec<
+        Rx: DeserializeOwned + 'static,
+        Tx: Serialize + DeserializeOwned + 'static,
+    >(
+        sess: impl Into<AnySession>,
+        codec: AnyCodec,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
+        let config = FrameworkConfig {
+            codec,
+            ..Default::default()
+        };
+        Self::new_with_config(sess, config).await
+    }
+
+    /// Creates a new framework, checking that the server agrees with the config and that
+    /// its root service has the same request and response types
+    pub async fn new_with_config<
+        Rx: DeserializeOwned + 'static,
+        Tx: Serialize + DeserializeOwned + 'static,
+    >(
+        sess: impl Into<AnySession>,
+        config: FrameworkConfig,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
+        let (inner, channel) = Framework::new(sess, Role::Client, config).await?;
+        Ok((Self { inner }, channel))
+    }
+
+    /// The symmetric framework underneath
+    pub fn framework(&self) -> &Framework {
+        &self.inner
+    }
+
+    /// See `Framework::using_codec()`
+    pub fn using_codec(&self, codec: AnyCodec) -> Self {
         Self {
-            seq: Arc::new(futures::lock::Mutex::new(sess)),
+            inner: self.inner.using_codec(codec),
         }
     }
 
-    // TODO: Typecheck that Client's types match Rx/Tx!!
-    pub fn accept_reverse_subservice<Rx: DeserializeOwned, Tx: Serialize, Client>(
-        &self,
-    ) -> (
-        OfferedService<Client>,
-        impl Future<Output = Result<impl Transport<Tx, Rx, Error = FrameworkError>, FrameworkError>>,
-    ) {
-        // Holds the lock only while we are opening the stream
-        let seq = self.seq.clone();
-        let channelfuture = async move {
-            let socks = {
-                let mut sess = seq.lock().await;
-                sess.accept_bi().await?
-            };
-
-            Ok(crate::io::webtransport_protocol(socks))
-        };
+    /// See `Framework::using_max_frame_size()`
+    pub fn using_max_frame_size(&self, max_frame_size: usize) -> Self {
+        Self {
+            inner: self.inner.using_max_frame_size(max_frame_size),
+        }
+    }
 
-        let sub = OfferedService {
-            _phantom: PhantomData,
-        };
+    /// See `Framework::using_max_decode_allocation()`
+    pub fn using_max_decode_allocation(&self, max_decode_allocation: usize) -> Self {
+        Self {
+            inner: self
+                .inner
+                .using_max_decode_allocation(max_decode_allocation),
+        }
+    }
 
-        (sub, channelfuture)
+    /// See `Framework::using_compression()`
+    pub fn using_compression(&self, compression: Compression, threshold: usize) -> Self {
+        Self {
+            inner: self.inner.using_compression(compression, threshold),
+        }
     }
 
-    // TODO: Typecheck that Client's types match Rx/Tx!!
-    pub async fn connect_subservice<Rx: DeserializeOwned, Tx: Serialize, Client>(
+    /// See `Framework::using_capability()`
+    pub fn using_capability(
         &self,
-        _token: Subservice<Client>,
-    ) -> Result<impl Transport<Tx, Rx, Error = FrameworkError>, FrameworkError>
-where
-        //Client: Stub<Req = Tx, Resp = Rx>,
-    {
-        // Holds the lock only while we are opening the stream
-        let socks = {
-            let mut sess = self.seq.lock().await;
-            //println!("Opening");
-            let ret = sess.open_bi().await?;
-            //println!("Done Opening");
-            ret
-        };
+        key: CapabilityKey,
+        scope: impl Into<String>,
+        ttl: Duration,
+    ) -> Self {
+        Self {
+            inner: self.inner.using_capability(key, scope, ttl),
+        }
+    }
 
-        Ok(crate::io::webtransport_protocol(socks))
+    /// Runs an RPC handler with a handle whose tokens are bound to the call, see
+    /// `Framework::in_call()`
+    pub async fn in_call<F, Fut>(&self, ctx: &tarpc::context::Context, handler: F) -> Fut::Output
+    where
+        F: FnOnce(Self) -> Fut,
+        Fut: Future,
+    {
+        self.inner
+            .in_call(ctx, |inner| handler(Self { inner }))
+            .await
     }
+}
 
-    // TODO: Typecheck that Client's types match Rx/Tx!!
-    pub async fn connect_bistream<Rx: DeserializeOwned, Tx: Serialize>(
-        &self,
-        _token: BiStream<Rx, Tx>,
-    ) -> Result<impl Transport<Tx, Rx, Error = FrameworkError>, FrameworkError> {
-        // Holds the lock only while we are opening the stream
-        let socks = {
-            let mut sess = self.seq.lock().await;
-            //println!("Opening");
-            let ret = sess.open_bi().await?;
-            //println!("Done Opening");
-            ret
-        };
+// Everything but the constructors and the handles which return `Self` comes from `Framework`
+impl std::ops::Deref for ClientFramework {
+    type Target = Framework;
 
-        Ok(crate::io::webtransport_protocol(socks))
+    fn deref(&self) -> &Framework {
+        &self.inner
     }
 }
 
+impl From<ClientFramework> for Framework {
+    fn from(value: ClientFramework) -> Self {
+        value.inner
+    }
+}
+
+/// The server end of a session, which waits for the root stream. A thin wrapper around
+/// `Framework`, whose methods it has through `Deref`.
 #[derive(Clone)]
 pub struct ServerFramework {
-    // Ensures each open() occurs in sequence with each accept(). We don't open() until the last
-    // one was either completed or failed!
-    pub seq: Arc<futures::lock::Mutex<Session>>,
+    inner: Framework,
 }
 
 impl ServerFramework {
     /// Creates a new framework, and offers a root transport
-    pub async fn new<Rx: DeserializeOwned, Tx: Serialize>(
-        mut sess: Session,
+    pub async fn new<Rx: DeserializeOwned + 'static, Tx: Serialize + DeserializeOwned + 'static>(
+        sess: impl Into<AnySession>,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
+        Self::new_with_config(sess, FrameworkConfig::default()).await
+    }
+
+    /// Creates a new framework using the given codec, which the client must also use
+    pub async fn new_with_codec<
+        Rx: DeserializeOwned + 'static,
+        Tx: Serialize + DeserializeOwned + 'static,
+    >(
+        sess: impl Into<AnySession>,
+        codec: AnyCodec,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
+        let config = FrameworkConfig {
+            codec,
+            ..Default::default()
+        };
+        Self::new_with_config(sess, config).await
+    }
+
+    /// Creates a new framework, checking that the client agrees with the config and that
+    /// its root service has the same request and response types
+    pub async fn new_with_config<
+        Rx: DeserializeOwned + 'static,
+        Tx: Serialize + DeserializeOwned + 'static,
+    >(
+        sess: impl Into<AnySession>,
+        config: FrameworkConfig,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
+        let (inner, channel) = Framework::new(sess, Role::Server, config).await?;
+        Ok((Self { inner }, channel))
+    }
+
+    /// Creates a new framework like `new_with_config()`, letting in only the clients whose
+    /// `FrameworkConfig::credentials` pass the authenticator. See `identity()` for who they are.
+    pub async fn new_with_auth<
+        Rx: DeserializeOwned + 'static,
+        Tx: Serialize + DeserializeOwned + 'static,
+    >(
+        sess: impl Into<AnySession>,
+        config: FrameworkConfig,
+        authenticator: impl Authenticator,
     ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
-        let socks = sess.accept_bi().await?;
-        let channel = crate::io::webtransport_protocol(socks);
-        let inst = Self::new_internal(sess);
-        Ok((inst, channel))
+        let (inner, channel) = Framework::new_with_auth(sess, config, authenticator).await?;
+        Ok((Self { inner }, channel))
+    }
+
+    /// The symmetric framework underneath
+    pub fn framework(&self) -> &Framework {
+        &self.inner
+    }
+
+    /// See `Framework::with_peer_info()`
+    pub fn with_peer_info(self, peer_info: PeerInfo) -> Self {
+        Self {
+            inner: self.inner.with_peer_info(peer_info),
+        }
+    }
+
+    /// See `Framework::using_codec()`
+    pub fn using_codec(&self, codec: AnyCodec) -> Self {
+        Self {
+            inner: self.inner.using_codec(codec),
+        }
     }
 
-    fn new_internal(sess: Session) -> Self {
+    /// See `Framework::using_max_frame_size()`
+    pub fn using_max_frame_size(&self, max_frame_size: usize) -> Self {
         Self {
-            seq: Arc::new(futures::lock::Mutex::new(sess)),
+            inner: self.inner.using_max_frame_size(max_frame_size),
         }
     }
 
-    // TODO: Typecheck that Client's types match Rx/Tx!!
-    pub async fn connect_reverse_service<Rx: DeserializeOwned, Tx: Serialize, Client>(
+    /// See `Framework::using_max_decode_allocation()`
+    pub fn using_max_decode_allocation(&self, max_decode_allocation: usize) -> Self {
+        Self {
+            inner: self
+                .inner
+                .using_max_decode_allocation(max_decode_allocation),
+        }
+    }
+
+    /// See `Framework::using_compression()`
+    pub fn using_compression(&self, compression: Compression, threshold: usize) -> Self {
+        Self {
+            inner: self.inner.using_compression(compression, threshold),
+        }
+    }
+
+    /// See `Framework::using_capability()`
+    pub fn using_capability(
         &self,
-        _token: OfferedService<Client>,
-    ) -> Result<impl Transport<Tx, Rx, Error = FrameworkError>, FrameworkError>
-where
-        //Client: Stub<Req = Tx, Resp = Rx>,
+        key: CapabilityKey,
+        scope: impl Into<String>,
+        ttl: Duration,
+    ) -> Self {
+        Self {
+            inner: self.inner.using_capability(key, scope, ttl),
+        }
+    }
+
+    /// Runs an RPC handler with a handle whose tokens are bound to the call, see
+    /// `Framework::in_call()`
+    pub async fn in_call<F, Fut>(&self, ctx: &tarpc::context::Context, handler: F) -> Fut::Output
+    where
+        F: FnOnce(Self) -> Fut,
+        Fut: Future,
     {
-        // Holds the lock only while we are opening the stream
-        let socks = {
-            let mut sess = self.seq.lock().await;
-            //println!("Opening");
-            let ret = sess.open_bi().await?;
-            //println!("Done Opening");
-            ret
-        };
+        self.inner
+            .in_call(ctx, |inner| handler(Self { inner }))
+            .await
+    }
+}
+
+// Everything but the constructors and the handles which return `Self` comes from `Framework`
+impl std::ops::Deref for ServerFramework {
+    type Target = Framework;
 
-        Ok(crate::io::webtransport_protocol(socks))
+    fn deref(&self) -> &Framework {
+        &self.inner
     }
+}
 
-    // TODO: Typecheck that Client's types match Rx/Tx!!
-    pub fn accept_subservice<Rx: DeserializeOwned, Tx: Serialize, Client>(
-        &self,
-    ) -> (
-        Subservice<Client>,
-        impl Future<Output = Result<impl Transport<Tx, Rx, Error = FrameworkError>, FrameworkError>>,
-    ) {
-        // Holds the lock only while we are opening the stream
-        let seq = self.seq.clone();
-        let channelfuture = async move {
-            let socks = {
-                let mut sess = seq.lock().await;
-                sess.accept_bi().await?
-            };
+impl From<ServerFramework> for Framework {
+    fn from(value: ServerFramework) -> Self {
+        value.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures::channel::oneshot;
+    use futures::{
+        future::{join_all, pending},
+        SinkExt, StreamExt,
+    };
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use tarpc::{
+        context,
+        server::{BaseChannel, Channel},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::io::{FrameworkError, CALL_CANCELLED_CODE};
+    use crate::session::MemorySession;
+    use crate::stats::{SessionStats, StreamKind};
+    use crate::{
+        Authenticator, BiStream, CapabilityKey, ClientFramework, FrameworkConfig, Identity,
+        ResultStreamError, ServerFramework,
+    };
+
+    #[tarpc::service]
+    trait Adder {
+        async fn add(a: u32, b: u32) -> u32;
+    }
+
+    crate::impl_service_client!(Adder);
+
+    #[derive(Clone)]
+    struct AdderServer;
+
+    impl Adder for AdderServer {
+        async fn add(self, _: context::Context, a: u32, b: u32) -> u32 {
+            a + b
+        }
+    }
+
+    struct Password(&'static str);
+
+    impl Authenticator for Password {
+        async fn authenticate(&self, credentials: Option<&str>) -> Result<Identity, String> {
+            match credentials {
+                Some(credentials) if credentials == self.0 => Ok(Identity {
+                    name: "alice".into(),
+                }),
+                _ => Err("wrong password".into()),
+            }
+        }
+    }
+
+    /// Creates both ends of a session in memory, along with their root transports, which are
+    /// kept alive for the test
+    async fn connect() -> (ClientFramework, ServerFramework, impl Sized) {
+        let (client_sess, server_sess) = MemorySession::pair();
+        let (client, server) = tokio::join!(
+            ClientFramework::new::<u32, String>(client_sess),
+            ServerFramework::new::<String, u32>(server_sess),
+        );
+        let (client, client_root) = client.unwrap();
+        let (server, server_root) = server.unwrap();
+        (client, server, (client_root, server_root))
+    }
+
+    /// Sends a token through serialization, as it would be in a call's response
+    fn wire<T: Serialize + DeserializeOwned>(token: T) -> T {
+        serde_json::from_str(&serde_json::to_string(&token).unwrap()).unwrap()
+    }
+
+    fn rejected(error: Option<FrameworkError>, expected: &str) -> bool {
+        matches!(error, Some(FrameworkError::CapabilityRejected(reason)) if reason == expected)
+    }
+
+    #[tokio::test]
+    async fn bistream_carries_messages_both_ways() {
+        let (client, server, _roots) = connect().await;
+
+        let (token, accepted) = server.accept_bistream::<String, u32>();
+        let (issuer, holder) = tokio::join!(accepted, client.connect_bistream(wire(token)));
+        let (mut issuer, mut holder) = (issuer.unwrap(), holder.unwrap());
+
+        issuer.send("ping".to_string()).await.unwrap();
+        assert_eq!(holder.next().await.unwrap().unwrap(), "ping");
+        holder.send(7).await.unwrap();
+        assert_eq!(issuer.next().await.unwrap().unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn downstream_and_upstream_end_when_the_sender_is_dropped() {
+        let (client, server, _roots) = connect().await;
+
+        let (token, accepted) = server.accept_downstream::<u32>();
+        let (sink, stream) = tokio::join!(accepted, client.connect_downstream(wire(token)));
+        let (mut sink, stream) = (sink.unwrap(), stream.unwrap());
+        sink.send(1).await.unwrap();
+        sink.send(2).await.unwrap();
+        drop(sink);
+        let received: Vec<u32> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(received, [1, 2]);
+
+        let (token, accepted) = server.accept_upstream::<u32>();
+        let (stream, sink) = tokio::join!(accepted, client.connect_upstream(wire(token)));
+        let (stream, mut sink) = (stream.unwrap(), sink.unwrap());
+        sink.send(3).await.unwrap();
+        drop(sink);
+        let received: Vec<u32> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(received, [3]);
+    }
 
-            Ok(crate::io::webtransport_protocol(socks))
+    #[tokio::test]
+    async fn result_stream_ends_with_the_issuers_error() {
+        let (client, server, _roots) = connect().await;
+
+        let results = futures::stream::iter([Ok(1), Ok(2), Err("broken".to_string()), Ok(3)]);
+        let (token, sent) = server.accept_result_stream::<u32, String>(results);
+        let (sent, stream) = tokio::join!(sent, client.connect_result_stream(wire(token)));
+        sent.unwrap();
+
+        let received: Vec<_> = stream.unwrap().collect().await;
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0].as_ref().unwrap(), &1);
+        assert_eq!(received[1].as_ref().unwrap(), &2);
+        assert!(matches!(&received[2], Err(ResultStreamError::Remote(e)) if e == "broken"));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum LookupError {
+        NotFound(String),
+    }
+
+    #[tokio::test]
+    async fn result_stream_carries_typed_errors_and_then_ends() {
+        let (client, server, _roots) = connect().await;
+
+        let results =
+            futures::stream::iter([Ok(1), Err(LookupError::NotFound("two".into())), Ok(3)]);
+        let (token, sent) = server.accept_result_stream::<u32, LookupError>(results);
+        let (sent, stream) = tokio::join!(sent, client.connect_result_stream(wire(token)));
+        sent.unwrap();
+
+        let mut stream = stream.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), 1);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(ResultStreamError::Remote(LookupError::NotFound(key)))) if key == "two"
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn result_stream_ends_with_its_last_item() {
+        let (client, server, _roots) = connect().await;
+
+        let results = futures::stream::iter([Ok(1), Ok(2)]);
+        let (token, sent) = server.accept_result_stream::<u32, LookupError>(results);
+        let (sent, stream) = tokio::join!(sent, client.connect_result_stream(wire(token)));
+        sent.unwrap();
+
+        let received: Vec<u32> = stream.unwrap().map(Result::unwrap).collect().await;
+        assert_eq!(received, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn dropping_a_result_stream_stops_the_issuer() {
+        let (client, server, _roots) = connect().await;
+
+        let results = futures::stream::repeat_with(|| Ok(1));
+        let (token, sent) = server.accept_result_stream::<u32, LookupError>(results);
+        let sent = tokio::spawn(sent);
+
+        let mut stream = client.connect_result_stream(wire(token)).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), 1);
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), sent)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn raw_stream_carries_bytes() {
+        let (client, server, _roots) = connect().await;
+
+        let (token, accepted) = server.accept_raw_stream();
+        let (issuer, holder) = tokio::join!(accepted, client.connect_raw_stream(wire(token)));
+        let (mut issuer, mut holder) = (issuer.unwrap(), holder.unwrap());
+
+        issuer.write_all(b"some file").await.unwrap();
+        issuer.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        holder.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"some file");
+    }
+
+    #[tokio::test]
+    async fn datagrams_arrive_both_ways() {
+        let (client, server, _roots) = connect().await;
+
+        let (token, mut issuer) = server.accept_datagrams::<u32, String>();
+        let mut holder = client.connect_datagrams(wire(token));
+
+        issuer.send(&5).await.unwrap();
+        assert_eq!(holder.next().await.unwrap().unwrap(), 5);
+        holder.send(&"pong".to_string()).await.unwrap();
+        assert_eq!(issuer.next().await.unwrap().unwrap(), "pong");
+    }
+
+    #[tokio::test]
+    async fn subservices_are_served_by_either_side() {
+        let (client, server, _roots) = connect().await;
+
+        // Offered by the server, called by the client
+        let (token, accepted) = server.accept_subservice::<AdderClient>();
+        let (serving, calling) = tokio::join!(accepted, client.connect_subservice(wire(token)));
+        tokio::spawn(
+            BaseChannel::with_defaults(serving.unwrap())
+                .execute(AdderServer.serve())
+                .for_each(|call| async {
+                    tokio::spawn(call);
+                }),
+        );
+        let adder = AdderClient::new(Default::default(), calling.unwrap());
+        tokio::spawn(adder.dispatch);
+        assert_eq!(adder.client.add(context::current(), 2, 3).await.unwrap(), 5);
+
+        // Offered by the client, called by the server
+        let (token, accepted) = client.accept_reverse_subservice::<AdderClient>();
+        let (serving, calling) =
+            tokio::join!(accepted, server.connect_reverse_service(wire(token)));
+        tokio::spawn(
+            BaseChannel::with_defaults(serving.unwrap())
+                .execute(AdderServer.serve())
+                .for_each(|call| async {
+                    tokio::spawn(call);
+                }),
+        );
+        let adder = AdderClient::new(Default::default(), calling.unwrap());
+        tokio::spawn(adder.dispatch);
+        assert_eq!(adder.client.add(context::current(), 4, 5).await.unwrap(), 9);
+    }
+
+    #[tokio::test]
+    async fn tokens_redeemed_at_once_are_routed_by_id() {
+        let (client, server, _roots) = connect().await;
+
+        let (tokens, accepted): (Vec<_>, Vec<_>) =
+            (0..8).map(|_| server.accept_bistream::<u32, u32>()).unzip();
+
+        let issuers = join_all(
+            accepted
+                .into_iter()
+                .zip(0..)
+                .map(|(accepted, i)| async move {
+                    let mut issuer = accepted.await.unwrap();
+                    issuer.send(i).await.unwrap();
+                    // Kept open until the holder has read
+                    issuer
+                }),
+        );
+        // Redeemed in the opposite order from the one they were issued in
+        let holders = join_all(tokens.into_iter().enumerate().rev().map(|(i, token)| {
+            let client = client.clone();
+            async move {
+                let mut holder = client.connect_bistream(wire(token)).await.unwrap();
+                assert_eq!(holder.next().await.unwrap().unwrap(), i as u32);
+            }
+        }));
+
+        tokio::join!(issuers, holders);
+    }
+
+    #[tokio::test]
+    async fn capability_is_accepted() {
+        let (client, server, _roots) = connect().await;
+        let server = server.using_capability(
+            CapabilityKey::new(b"secret"),
+            "room",
+            Duration::from_secs(60),
+        );
+
+        let (token, accepted) = server.accept_bistream::<u32, u32>();
+        assert_eq!(token.capability.as_ref().unwrap().scope(), "room");
+        let (issuer, holder) = tokio::join!(accepted, client.connect_bistream(wire(token)));
+        let (mut issuer, mut holder) = (issuer.unwrap(), holder.unwrap());
+
+        issuer.send(1).await.unwrap();
+        assert_eq!(holder.next().await.unwrap().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn tampered_capability_is_rejected() {
+        let (client, server, _roots) = connect().await;
+        let server = server.using_capability(
+            CapabilityKey::new(b"secret"),
+            "room",
+            Duration::from_secs(60),
+        );
+
+        let (token, accepted) = server.accept_bistream::<u32, u32>();
+        let mut value = serde_json::to_value(token).unwrap();
+        value["capability"]["scope"] = "admin".into();
+        let token: BiStream<u32, u32> = serde_json::from_value(value).unwrap();
+
+        let (issuer, _holder) = tokio::join!(accepted, client.connect_bistream(token));
+        assert!(rejected(issuer.err(), "bad signature"));
+    }
+
+    /// A copy of the token whose capability claims another scope, which breaks its signature
+    fn forged<T: Serialize + DeserializeOwned>(token: &T) -> T {
+        let mut value = serde_json::to_value(token).unwrap();
+        value["capability"]["scope"] = "admin".into();
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn signing(server: &ServerFramework) -> ServerFramework {
+        server.using_capability(
+            CapabilityKey::new(b"secret"),
+            "room",
+            Duration::from_secs(60),
+        )
+    }
+
+    #[tokio::test]
+    async fn one_way_streams_check_the_capability() {
+        let (client, server, _roots) = connect().await;
+        let server = signing(&server);
+
+        let (token, accepted) = server.accept_downstream::<u32>();
+        let (sink, stream) = tokio::join!(accepted, client.connect_downstream(wire(token)));
+        sink.unwrap().send(1).await.unwrap();
+        assert_eq!(stream.unwrap().next().await.unwrap().unwrap(), 1);
+
+        let (token, accepted) = server.accept_upstream::<u32>();
+        let (stream, sink) = tokio::join!(accepted, client.connect_upstream(wire(token)));
+        sink.unwrap().send(2).await.unwrap();
+        assert_eq!(stream.unwrap().next().await.unwrap().unwrap(), 2);
+
+        let (token, accepted) = server.accept_upstream::<u32>();
+        let (issuer, _holder) = tokio::join!(accepted, client.connect_upstream(forged(&token)));
+        assert!(rejected(issuer.err(), "bad signature"));
+
+        // The holder waits for a stream which never comes
+        let (token, accepted) = server.accept_downstream::<u32>();
+        let holder = client.connect_downstream(forged(&token));
+        let issuer = tokio::select! {
+            issuer = accepted => issuer,
+            _ = holder => unreachable!(),
         };
+        assert!(rejected(issuer.err(), "bad signature"));
+    }
+
+    #[tokio::test]
+    async fn datagrams_are_received_only_from_the_holder_of_the_capability() {
+        let (client, server, _roots) = connect().await;
+        let server = signing(&server);
 
-        let sub = Subservice {
-            _phantom: PhantomData,
+        let (token, mut issuer) = server.accept_datagrams::<u32, u32>();
+        let mut holder = client.connect_datagrams(forged(&token));
+        for _ in 0..5 {
+            holder.send(&1).await.unwrap();
+            crate::sleep(Duration::from_millis(10)).await;
+        }
+        let next = tokio::time::timeout(Duration::from_millis(100), issuer.next());
+        assert!(next.await.is_err());
+
+        // Those sent before the capability is verified may be dropped
+        let (token, mut issuer) = server.accept_datagrams::<u32, u32>();
+        let mut holder = client.connect_datagrams(wire(token));
+        let received = loop {
+            holder.send(&2).await.unwrap();
+            let next = tokio::time::timeout(Duration::from_millis(20), issuer.next());
+            if let Ok(received) = next.await {
+                break received;
+            }
         };
+        assert_eq!(received.unwrap().unwrap(), 2);
+    }
 
-        (sub, channelfuture)
+    #[tokio::test]
+    async fn only_the_holder_of_the_capability_can_discard() {
+        let (client, server, _roots) = connect().await;
+        let server = signing(&server);
+
+        let (token, accepted) = server.accept_bistream::<u32, u32>();
+        client.discard(forged(&token)).await.unwrap();
+        let mut accepted = Box::pin(accepted);
+        let waiting = tokio::time::timeout(Duration::from_millis(100), &mut accepted);
+        assert!(waiting.await.is_err());
+
+        // The token can still be redeemed, or discarded by its holder
+        let (issuer, holder) = tokio::join!(accepted, client.connect_bistream(token));
+        issuer.unwrap().send(1).await.unwrap();
+        assert_eq!(holder.unwrap().next().await.unwrap().unwrap(), 1);
+
+        let (token, accepted) = server.accept_raw_stream();
+        let (issuer, discarded) = tokio::join!(accepted, client.discard(wire(token)));
+        discarded.unwrap();
+        assert!(matches!(issuer.err(), Some(FrameworkError::TokenDiscarded)));
     }
 
-    // TODO: Typecheck that Client's types match Rx/Tx!!
-    pub fn accept_bistream<Rx: DeserializeOwned, Tx: Serialize>(
-        &self,
-    ) -> (
-        BiStream<Rx, Tx>,
-        impl Future<Output = Result<impl Transport<Tx, Rx, Error = FrameworkError>, FrameworkError>>,
-    ) {
-        // Holds the lock only while we are opening the stream
-        let seq = self.seq.clone();
-        let channelfuture = async move {
-            let socks = {
-                let mut sess = seq.lock().await;
-                sess.accept_bi().await?
+    #[tokio::test]
+    async fn expired_capability_is_rejected() {
+        let (client, server, _roots) = connect().await;
+        let server = server.using_capability(CapabilityKey::new(b"secret"), "room", Duration::ZERO);
+
+        let (token, accepted) = server.accept_raw_stream();
+        // Expiry has a resolution of a second
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let (issuer, _holder) = tokio::join!(accepted, client.connect_raw_stream(wire(token)));
+        assert!(rejected(issuer.err(), "expired"));
+    }
+
+    #[tokio::test]
+    async fn capability_from_another_session_is_rejected() {
+        let key = CapabilityKey::new(b"secret");
+        let ttl = Duration::from_secs(60);
+        let (_client, server, _roots) = connect().await;
+        let (other_client, other_server, _other_roots) = connect().await;
+
+        // Both sessions number their tokens the same way, so only the session tells them apart
+        let (token, _accepted) = server
+            .using_capability(key.clone(), "room", ttl)
+            .accept_bistream::<u32, u32>();
+        let (other_token, other_accepted) = other_server
+            .using_capability(key, "room", ttl)
+            .accept_bistream::<u32, u32>();
+        assert_eq!(token.id, other_token.id);
+
+        let (issuer, _holder) =
+            tokio::join!(other_accepted, other_client.connect_bistream(wire(token)));
+        assert!(rejected(issuer.err(), "issued on another session"));
+    }
+
+    #[tokio::test]
+    async fn authenticator_lets_in_only_valid_credentials() {
+        async fn attempt(
+            credentials: &str,
+        ) -> (
+            Option<FrameworkError>,
+            Result<ServerFramework, FrameworkError>,
+        ) {
+            let (client_sess, server_sess) = MemorySession::pair();
+            let config = FrameworkConfig {
+                credentials: Some(credentials.into()),
+                ..Default::default()
             };
+            let (client, server) = tokio::join!(
+                ClientFramework::new_with_config::<u32, String>(client_sess, config),
+                ServerFramework::new_with_auth::<String, u32>(
+                    server_sess,
+                    FrameworkConfig::default(),
+                    Password("hunter2"),
+                ),
+            );
+            (client.err(), server.map(|(server, _root)| server))
+        }
+
+        let (client, server) = attempt("guess").await;
+        assert!(matches!(client, Some(FrameworkError::Unauthorized(_))));
+        assert!(
+            matches!(server.err(), Some(FrameworkError::Unauthorized(reason)) if reason == "wrong password")
+        );
+
+        let (client, server) = attempt("hunter2").await;
+        assert!(client.is_none());
+        assert_eq!(server.unwrap().identity().unwrap().name, "alice");
+    }
+
+    #[tokio::test]
+    async fn discarded_token_fails_the_accept() {
+        let (client, server, _roots) = connect().await;
+
+        let (token, accepted) = server.accept_bistream::<u32, u32>();
+        let (issuer, discarded) = tokio::join!(accepted, client.discard(wire(token)));
+        discarded.unwrap();
+        assert!(matches!(issuer.err(), Some(FrameworkError::TokenDiscarded)));
+    }
+
+    #[tokio::test]
+    async fn unconnected_token_times_out() {
+        let (_client, server, _roots) = connect().await;
+        server.set_accept_timeout(Some(Duration::from_millis(50)));
+
+        // Dropped by the holder, which nothing tells the issuer about
+        let (_token, accepted) = server.accept_raw_stream();
+        assert!(matches!(
+            accepted.await.err(),
+            Some(FrameworkError::ConnectTimeout(timeout)) if timeout == Duration::from_millis(50)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tokens_issued_in_a_call_fail_at_its_deadline() {
+        let (_client, server, _roots) = connect().await;
+
+        let mut ctx = context::current();
+        ctx.deadline = Instant::now() + Duration::from_millis(50);
+        let accepted = server
+            .in_call(&ctx, |server| async move {
+                let (_token, accepted) = server.accept_downstream::<u32>();
+                accepted.await
+            })
+            .await;
+        assert!(matches!(
+            accepted.err(),
+            Some(FrameworkError::DeadlineExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tokens_issued_in_a_cancelled_call_fail() {
+        let (client, server, _roots) = connect().await;
+
+        // tarpc cancels a call by dropping its handler
+        let (issued, token) = oneshot::channel();
+        let call = server.in_call(&context::current(), |server| async move {
+            let _ = issued.send(server.accept_bistream::<u32, u32>());
+            pending::<()>().await
+        });
+        assert!(tokio::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+
+        let (token, accepted) = token.await.unwrap();
+        assert!(matches!(
+            accepted.await.err(),
+            Some(FrameworkError::Cancelled)
+        ));
+
+        // The holder doesn't know, and is turned away when it connects anyway
+        let mut holder = client.connect_bistream(wire(token)).await.unwrap();
+        loop {
+            match holder.send(1).await {
+                Ok(()) => crate::sleep(Duration::from_millis(5)).await,
+                Err(FrameworkError::StreamReset { code }) => {
+                    break assert_eq!(code, CALL_CANCELLED_CODE)
+                }
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_count_the_traffic_of_each_stream() {
+        let (client, server, _roots) = connect().await;
+
+        let (token, accepted) = server.accept_downstream::<u32>();
+        let (sink, stream) = tokio::join!(accepted, client.connect_downstream(wire(token)));
+        let (mut sink, mut stream) = (sink.unwrap(), stream.unwrap());
+        for i in 0..3 {
+            sink.send(i).await.unwrap();
+            stream.next().await.unwrap().unwrap();
+        }
 
-            Ok(crate::io::webtransport_protocol(socks))
+        let downstream = |stats: SessionStats| {
+            let mut streams = stats.streams.into_iter();
+            streams
+                .find(|s| s.kind == StreamKind::Downstream)
+                .unwrap()
+                .traffic
         };
+        let sent = downstream(server.stats());
+        let received = downstream(client.stats());
+        assert_eq!(sent.messages_sent, 3);
+        assert_eq!(received.messages_received, 3);
+        assert!(sent.bytes_sent > 0);
+        assert_eq!(received.bytes_received, sent.bytes_sent);
 
-        let sub = BiStream {
-            _phantom: PhantomData,
+        // Closed streams still count in the totals
+        drop((sink, stream));
+        let total = server.stats().total();
+        assert!(total.messages_sent >= 3);
+        assert!(total.bytes_sent >= sent.bytes_sent);
+    }
+
+    #[tokio::test]
+    async fn close_code_and_reason_reach_the_peer() {
+        let (client, server, _roots) = connect().await;
+
+        let (_, closed) = tokio::join!(server.close(7, "going away"), client.closed());
+        assert!(matches!(
+            closed,
+            FrameworkError::Closed { code: 7, reason } if reason == "going away"
+        ));
+    }
+
+    #[tokio::test]
+    async fn long_close_reason_is_cut_short() {
+        let (client, server, _roots) = connect().await;
+        let reason = "é".repeat(1000);
+
+        let (_, closed) = tokio::join!(client.close(8, &reason), server.closed());
+        let FrameworkError::Closed {
+            code,
+            reason: received,
+        } = closed
+        else {
+            panic!("expected a close, got {closed}");
         };
+        assert_eq!(code, 8);
+        assert!(!received.is_empty() && received.len() < 1024);
+        assert!(reason.starts_with(&received));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_resets_only_that_stream() {
+        let (client, server, _roots) = connect().await;
+        let small = client.using_max_frame_size(64);
+
+        let (token, accepted) = server.accept_downstream::<Vec<u8>>();
+        let (sink, stream) = tokio::join!(accepted, small.connect_downstream(wire(token)));
+        let (mut sink, mut stream) = (sink.unwrap(), stream.unwrap());
+        sink.send(vec![0; 1000]).await.unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(FrameworkError::FrameTooLarge { max: 64, .. }))
+        ));
+
+        // The rest of the session carries on
+        let (token, accepted) = server.accept_bistream::<u32, u32>();
+        let (issuer, holder) = tokio::join!(accepted, client.connect_bistream(wire(token)));
+        issuer.unwrap().send(1).await.unwrap();
+        assert_eq!(holder.unwrap().next().await.unwrap().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn messages_over_the_decode_limit_are_rejected() {
+        let (client, server, _roots) = connect().await;
+        let small = client.using_max_decode_allocation(100);
 
-        (sub, channelfuture)
+        let (token, accepted) = server.accept_downstream::<Vec<u8>>();
+        let (sink, stream) = tokio::join!(accepted, small.connect_downstream(wire(token)));
+        let (mut sink, mut stream) = (sink.unwrap(), stream.unwrap());
+        sink.send(vec![0; 50]).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().len(), 50);
+        sink.send(vec![0; 200]).await.unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(FrameworkError::Decode { .. }))
+        ));
     }
 }
@@ -0,0 +1,813 @@
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+    Future, FutureExt, Sink, Stream,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tarpc::{ClientMessage, Response, Transport};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::auth::{Authenticator, Identity, AUTH_FAILED_CODE};
+use crate::capability::{Capability, CapabilityKey, Signer};
+use crate::codec::AnyCodec;
+use crate::compression::Compression;
+use crate::datagram::{DatagramChannel, DatagramRouter};
+use crate::handshake::FrameworkConfig;
+use crate::io::{FrameOptions, FrameworkError, SplitTransport};
+use crate::peer_info::PeerInfo;
+use crate::result_stream::ResultStreamError;
+use crate::router::{CallScope, StreamHeader, StreamId, StreamRouter, ROOT_STREAM};
+use crate::session::{AnyRecvStream, AnySendStream, AnySession};
+use crate::stats::{SessionStats, StatsRegistry, StreamCounter, StreamKind};
+use crate::{
+    BiStream, Datagrams, Downstream, OfferedService, RawStream, ResultStream, ServiceClient,
+    StreamToken, Subservice, Upstream,
+};
+
+/// How messages on a stream go over the wire. The issuer of a token picks these, and the token
+/// carries them to the holder.
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct StreamOptions {
+    codec: AnyCodec,
+    frames: FrameOptions,
+}
+
+impl StreamOptions {
+    /// Options for redeeming a token; never accept larger frames or messages than we would
+    /// ourselves
+    fn redeem(&self, token: StreamOptions) -> Self {
+        let mut options = token;
+        options.frames.max_frame_size = token.frames.max_frame_size.min(self.frames.max_frame_size);
+        options.frames.max_decode_allocation = token
+            .frames
+            .max_decode_allocation
+            .min(self.frames.max_decode_allocation);
+        options
+    }
+
+    /// The receiving half ends cleanly once the session is closed on purpose
+    fn protocol<Rx: DeserializeOwned, Tx: Serialize>(
+        self,
+        (tx, rx): (AnySendStream, AnyRecvStream),
+        counter: StreamCounter,
+        router: &StreamRouter,
+    ) -> impl Transport<Tx, Rx, Error = FrameworkError> {
+        SplitTransport {
+            sink: self.sender(tx, counter.clone()),
+            stream: Box::pin(router.until_closed(self.receiver(rx, counter))),
+        }
+    }
+
+    fn sender<T: Serialize>(
+        self,
+        tx: AnySendStream,
+        counter: StreamCounter,
+    ) -> impl Sink<T, Error = FrameworkError> + Unpin {
+        crate::io::counted_sender(tx, self.codec, self.frames, counter)
+    }
+
+    fn receiver<T: DeserializeOwned>(
+        self,
+        rx: AnyRecvStream,
+        counter: StreamCounter,
+    ) -> impl Stream<Item = Result<T, FrameworkError>> + Unpin {
+        crate::io::counted_receiver(rx, self.codec, self.frames, counter)
+    }
+}
+
+/// Which end of the session a framework is on. The only differences are that the client opens
+/// the root stream, and the server waits for it and decides whether to let the client in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// One end of a session. Either end can issue any kind of token (the `accept_*` methods) and
+/// redeem the tokens issued by the other end (the `connect_*` methods).
+#[derive(Clone)]
+pub struct Framework {
+    // Streams opened by the peer are matched to their tokens by id
+    router: StreamRouter,
+    datagrams: DatagramRouter,
+    // Used for the root transport, and for the tokens we issue
+    options: StreamOptions,
+    peer_version: u32,
+    // Who the client is, on a server which authenticates its clients
+    identity: Option<Identity>,
+    // Where the client came from, if the server was told
+    peer_info: Option<Arc<PeerInfo>>,
+    // Bounds the accept futures of the tokens we issue, see `in_call()`
+    scope: CallScope,
+    // Random for each session, and bound into the capabilities we sign
+    session: u64,
+    signer: Option<Signer>,
+    stats: StatsRegistry,
+}
+
+/// Don't worry about it
+#[cfg(target_arch = "wasm32")]
+unsafe impl Send for Framework {}
+
+impl Framework {
+    /// Creates a new framework, checking that the peer agrees with the config and that its
+    /// root service has the same request and response types, and offers a root transport
+    pub async fn new<Rx: DeserializeOwned + 'static, Tx: Serialize + DeserializeOwned + 'static>(
+        sess: impl Into<AnySession>,
+        role: Role,
+        config: FrameworkConfig,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
+        Self::establish(sess, role, config, |_| async { Ok(None) }).await
+    }
+
+    /// Creates the server end of a session, like `new()`, letting in only the clients whose
+    /// credentials pass the authenticator. Rejected clients fail with
+    /// `FrameworkError::Unauthorized`, and the session is closed with `AUTH_FAILED_CODE`.
+    pub async fn new_with_auth<
+        Rx: DeserializeOwned + 'static,
+        Tx: Serialize + DeserializeOwned + 'static,
+    >(
+        sess: impl Into<AnySession>,
+        config: FrameworkConfig,
+        authenticator: impl Authenticator,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError> {
+        Self::establish(sess, Role::Server, config, |credentials| async move {
+            let identity = authenticator.authenticate(credentials.as_deref()).await?;
+            Ok(Some(identity))
+        })
+        .await
+    }
+
+    async fn establish<Rx, Tx, F, Fut>(
+        sess: impl Into<AnySession>,
+        role: Role,
+        config: FrameworkConfig,
+        authenticate: F,
+    ) -> Result<(Self, impl Transport<Tx, Rx, Error = FrameworkError>), FrameworkError>
+    where
+        Rx: DeserializeOwned + 'static,
+        Tx: Serialize + DeserializeOwned + 'static,
+        F: FnOnce(Option<String>) -> Fut,
+        Fut: Future<Output = Result<Option<Identity>, String>>,
+    {
+        let sess = sess.into();
+        let router = StreamRouter::new(sess.clone());
+        // The server must be waiting for the root stream before it can arrive
+        let root = (role == Role::Server).then(|| router.register_root());
+        router.run();
+
+        let mut socks = match root {
+            Some(root) => root.await?,
+            None => router.open_bi(ROOT_STREAM).await?,
+        };
+        let peer = crate::handshake::handshake::<Rx, Tx>(&mut socks, role, &config).await?;
+
+        let identity = match role {
+            Role::Client => {
+                crate::auth::receive_verdict(&mut socks.1).await?;
+                None
+            }
+            Role::Server => {
+                let verdict = authenticate(peer.credentials).await;
+                let accepted = verdict.as_ref().map(|_| ()).map_err(Clone::clone);
+                crate::auth::send_verdict(&mut socks.0, &accepted).await?;
+                match verdict {
+                    Ok(identity) => identity,
+                    Err(reason) => {
+                        router.close(AUTH_FAILED_CODE, &reason).await;
+                        return Err(FrameworkError::Unauthorized(reason));
+                    }
+                }
+            }
+        };
+
+        let options = StreamOptions {
+            codec: config.codec,
+            frames: config.frames,
+        };
+        let stats = StatsRegistry::default();
+        let channel = options.protocol(
+            socks,
+            stats.counter(StreamKind::Root, config.service.clone()),
+            &router,
+        );
+        let datagrams = DatagramRouter::new(sess);
+        let inst = Self {
+            router,
+            datagrams,
+            options,
+            peer_version: peer.version,
+            identity,
+            peer_info: None,
+            scope: CallScope::default(),
+            session: crate::capability::random_session(),
+            signer: None,
+            stats,
+        };
+        Ok((inst, channel))
+    }
+
+    /// Traffic of the framed streams of this session so far, shared by all clones
+    pub fn stats(&self) -> SessionStats {
+        self.stats.snapshot()
+    }
+
+    /// Adds this session's traffic since the last call to the `metrics` crate's counters,
+    /// labelled by kind and service. Call it periodically, e.g. before each scrape.
+    #[cfg(feature = "metrics")]
+    pub fn record_metrics(&self) {
+        self.stats.record_metrics();
+    }
+
+    /// Counts the traffic of a new stream, tagged with the name of `T`
+    fn counter<T: ?Sized>(&self, kind: StreamKind) -> StreamCounter {
+        self.stats.counter(kind, std::any::type_name::<T>())
+    }
+
+    /// Who the client is, as decided by the `Authenticator` given to `new_with_auth()`. `None`
+    /// on clients, and on servers which don't authenticate.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// The version the peer put in its `FrameworkConfig`
+    pub fn peer_version(&self) -> u32 {
+        self.peer_version
+    }
+
+    /// Attaches what is known about the peer's connection, for `peer_info()`. Call this right
+    /// after creating the framework, since handles cloned before don't see it.
+    pub fn with_peer_info(mut self, peer_info: PeerInfo) -> Self {
+        self.peer_info = Some(Arc::new(peer_info));
+        self
+    }
+
+    /// The peer's connection, as given to `with_peer_info()`
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_deref()
+    }
+
+    /// Returns a handle which issues tokens using the given codec, e.g. for a service with
+    /// large payloads. The holders of those tokens pick up the codec automatically.
+    pub fn using_codec(&self, codec: AnyCodec) -> Self {
+        let mut inst = self.clone();
+        inst.options.codec = codec;
+        inst
+    }
+
+    /// Returns a handle whose streams reject frames larger than `max_frame_size` bytes with
+    /// `FrameworkError::FrameTooLarge`, resetting just that stream. Applies to the tokens it
+    /// issues (for both ends) and the tokens it connects. Defaults to `DEFAULT_MAX_FRAME_SIZE`.
+    pub fn using_max_frame_size(&self, max_frame_size: usize) -> Self {
+        let mut inst = self.clone();
+        inst.options.frames.max_frame_size = max_frame_size;
+        inst
+    }
+
+    /// Returns a handle whose streams fail with `FrameworkError::Decode` on messages whose
+    /// strings and collections claim more than `max_decode_allocation` bytes, whatever their
+    /// length prefixes say. Applies like `using_max_frame_size()`. Defaults to
+    /// `DEFAULT_MAX_DECODE_ALLOCATION`.
+    pub fn using_max_decode_allocation(&self, max_decode_allocation: usize) -> Self {
+        let mut inst = self.clone();
+        inst.options.frames.max_decode_allocation = max_decode_allocation;
+        inst
+    }
+
+    /// Returns a handle whose tokens carry a capability signed with `key`, granting `scope`
+    /// until `ttl` has passed. Redeeming one of them fails with
+    /// `FrameworkError::CapabilityRejected` unless the holder presents that same capability, on
+    /// the session which issued it, before it expires. Applies to every kind of token, and to
+    /// `discard()`, so that only the holder can discard one. The holder of an `Upstream`
+    /// presents it on the stream it sends on, and that of a `Downstream` or `Datagrams` on a
+    /// stream of its own, which the issuer waits for before it sends, or receives datagrams.
+    pub fn using_capability(
+        &self,
+        key: CapabilityKey,
+        scope: impl Into<String>,
+        ttl: Duration,
+    ) -> Self {
+        let mut inst = self.clone();
+        inst.signer = Some(Signer {
+            key,
+            scope: scope.into(),
+            ttl,
+            session: self.session,
+        });
+        inst
+    }
+
+    /// Returns a handle which issues tokens whose frames are compressed, except for those
+    /// smaller than `threshold` bytes. The holders of those tokens pick up the compression
+    /// automatically, or fail loudly if they don't support it.
+    pub fn using_compression(&self, compression: Compression, threshold: usize) -> Self {
+        let mut inst = self.clone();
+        inst.options.frames.compression = compression;
+        inst.options.frames.compression_threshold = threshold;
+        inst
+    }
+
+    /// Closes the session, telling the peer the code and reason. Streams end cleanly on both
+    /// sides, and later operations fail with `FrameworkError::Closed`. Reasons are cut short
+    /// at about 1 KB.
+    pub async fn close(&self, code: u32, reason: &str) {
+        self.router.close(code, reason).await
+    }
+
+    /// Waits for the session to end, returning why
+    pub async fn closed(&self) -> FrameworkError {
+        self.router.closed().await;
+        self.router.end_error()
+    }
+
+    /// Sets how long the futures returned by `accept_*` wait for the peer to connect the
+    /// token before failing with `FrameworkError::ConnectTimeout`. `None` waits forever.
+    /// Defaults to `DEFAULT_ACCEPT_TIMEOUT`, and applies to all clones of this framework.
+    /// This is also how tokens the peer dropped without `discard()` are given up on, since
+    /// dropping one doesn't tell the session. Streams the peer opens for tokens we haven't
+    /// redeemed yet, or whose header doesn't arrive, are stopped after this long either way
+    /// (`DEFAULT_ACCEPT_TIMEOUT` if `None`), with `STREAM_REFUSED_CODE`.
+    pub fn set_accept_timeout(&self, timeout: Option<Duration>) {
+        self.router.set_accept_timeout(timeout);
+    }
+
+    /// Runs an RPC handler with a handle whose tokens are bound to the call with the given
+    /// context. Their accept futures fail with `FrameworkError::DeadlineExceeded` once the
+    /// call's deadline passes, and with `FrameworkError::Cancelled` if the call is cancelled
+    /// before the handler returns. A stream the peer opens for a cancelled token anyway is
+    /// reset with `CALL_CANCELLED_CODE`. Deadlines aren't applied in the browser, where
+    /// `Instant` isn't available.
+    pub async fn in_call<F, Fut>(&self, ctx: &tarpc::context::Context, handler: F) -> Fut::Output
+    where
+        F: FnOnce(Self) -> Fut,
+        Fut: Future,
+    {
+        // tarpc drops the handler when the call is cancelled, which drops `done` unsent
+        let (done, finished) = oneshot::channel();
+        let mut inst = self.clone();
+        inst.scope = CallScope {
+            deadline: cfg!(not(target_arch = "wasm32")).then_some(ctx.deadline),
+            done: Some(finished.shared()),
+        };
+
+        let output = handler(inst).await;
+        let _ = done.send(());
+        output
+    }
+
+    /// Tells the peer that the token won't be connected, failing its accept future with
+    /// `FrameworkError::TokenDiscarded`. The token's capability, if it has one, must check out,
+    /// or the peer keeps waiting. Tokens which are dropped instead are only given up on once
+    /// the accept timeout passes.
+    pub async fn discard(&self, token: impl StreamToken) -> Result<(), FrameworkError> {
+        self.present_uni(token.stream_id(), token.capability())
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the holder of the token to open its bidirectional stream
+    fn serve<Client: ServiceClient>(
+        &self,
+        kind: StreamKind,
+    ) -> (
+        StreamId,
+        Option<Capability>,
+        impl Future<
+            Output = Result<
+                impl Transport<
+                    Response<Client::Resp>,
+                    ClientMessage<Client::Req>,
+                    Error = FrameworkError,
+                >,
+                FrameworkError,
+            >,
+        >,
+    ) {
+        let (id, capability, socks) = self.register();
+        let options = self.options;
+        let counter = self.counter::<Client>(kind);
+        let router = self.router.clone();
+        let channelfuture = async move { Ok(options.protocol(socks.await?, counter, &router)) };
+        (id, capability, channelfuture)
+    }
+
+    /// Reserves an id for a token which the holder redeems by opening a bidirectional stream,
+    /// signing a capability for it if this handle signs them
+    fn register(
+        &self,
+    ) -> (
+        StreamId,
+        Option<Capability>,
+        impl Future<Output = Result<(AnySendStream, AnyRecvStream), FrameworkError>>,
+    ) {
+        let (id, socks) = self.router.register(&self.scope);
+        // The holder opens a unidirectional stream instead to discard the token
+        let discarded = self
+            .router
+            .wait_uni(StreamHeader::redeemed(id), &self.scope);
+        let (router, scope) = (self.router.clone(), self.scope.clone());
+        let signer = self.signer.clone();
+        let capability = signer.as_ref().map(|signer| signer.sign(id));
+        let socksfuture = async move {
+            let (mut socks, mut discarded) = (Box::pin(socks), Box::pin(discarded));
+            let mut socks = loop {
+                match select(socks, discarded).await {
+                    Either::Left((socks, _)) => break socks?,
+                    Either::Right((rx, rest)) => {
+                        let mut rx = rx?;
+                        socks = rest;
+                        discarded = Box::pin(router.wait_uni(StreamHeader::redeemed(id), &scope));
+
+                        // Only the holder may discard a token which carries a capability
+                        let genuine = match &signer {
+                            Some(signer) => signer.verify(id, &mut rx).await.is_ok(),
+                            None => true,
+                        };
+                        if genuine {
+                            return Err(FrameworkError::TokenDiscarded);
+                        }
+                    }
+                }
+            };
+            if let Some(signer) = signer {
+                signer.verify(id, &mut socks.1).await?;
+            }
+            Ok(socks)
+        };
+        (id, capability, socksfuture)
+    }
+
+    /// Reserves an id for a token which the holder redeems without a bidirectional stream,
+    /// signing a capability for it if this handle signs them. The future waits for the holder
+    /// to present it on a unidirectional stream, if there is one.
+    fn register_uni(
+        &self,
+    ) -> (
+        StreamId,
+        Option<Capability>,
+        impl Future<Output = Result<(), FrameworkError>>,
+    ) {
+        let id = self.router.next_id();
+        let signer = self.signer.clone();
+        let capability = signer.as_ref().map(|signer| signer.sign(id));
+        let proof = signer.is_some().then(|| {
+            self.router
+                .wait_uni(StreamHeader::redeemed(id), &self.scope)
+        });
+        let verified = async move {
+            if let (Some(signer), Some(proof)) = (signer, proof) {
+                signer.verify(id, &mut proof.await?).await?;
+            }
+            Ok(())
+        };
+        (id, capability, verified)
+    }
+
+    /// Presents the capability of a token which isn't redeemed with a bidirectional stream, on a
+    /// unidirectional one
+    async fn present_uni(
+        &self,
+        id: StreamId,
+        capability: Option<&Capability>,
+    ) -> Result<AnySendStream, FrameworkError> {
+        let mut tx = self.router.open_uni(StreamHeader::redeemed(id)).await?;
+        if let Some(capability) = capability {
+            crate::capability::present(&mut tx, capability).await?;
+        }
+        Ok(tx)
+    }
+
+    /// Opens the stream for a token, presenting its capability if it has one
+    async fn redeem(
+        &self,
+        id: StreamId,
+        capability: Option<&Capability>,
+    ) -> Result<(AnySendStream, AnyRecvStream), FrameworkError> {
+        let mut socks = self.router.open_bi(id).await?;
+        if let Some(capability) = capability {
+            crate::capability::present(&mut socks.0, capability).await?;
+        }
+        Ok(socks)
+    }
+
+    /// Offers a service to the peer. We serve it on the returned transport, and the peer
+    /// calls it with `connect_subservice()`.
+    pub fn accept_subservice<Client: ServiceClient>(
+        &self,
+    ) -> (
+        Subservice<Client>,
+        impl Future<
+            Output = Result<
+                impl Transport<
+                    Response<Client::Resp>,
+                    ClientMessage<Client::Req>,
+                    Error = FrameworkError,
+                >,
+                FrameworkError,
+            >,
+        >,
+    ) {
+        let (id, capability, channelfuture) = self.serve::<Client>(StreamKind::Subservice);
+
+        let sub = Subservice {
+            id,
+            options: self.options,
+            capability,
+            _phantom: PhantomData,
+        };
+
+        (sub, channelfuture)
+    }
+
+    pub async fn connect_subservice<Client: ServiceClient>(
+        &self,
+        token: Subservice<Client>,
+    ) -> Result<
+        impl Transport<ClientMessage<Client::Req>, Response<Client::Resp>, Error = FrameworkError>,
+        FrameworkError,
+    > {
+        let socks = self.redeem(token.id, token.capability.as_ref()).await?;
+        Ok(self.options.redeem(token.options).protocol(
+            socks,
+            self.counter::<Client>(StreamKind::Subservice),
+            &self.router,
+        ))
+    }
+
+    /// Offers a service to the peer, like `accept_subservice()`, for the peer to call with
+    /// `connect_reverse_service()`
+    pub fn accept_reverse_subservice<Client: ServiceClient>(
+        &self,
+    ) -> (
+        OfferedService<Client>,
+        impl Future<
+            Output = Result<
+                impl Transport<
+                    Response<Client::Resp>,
+                    ClientMessage<Client::Req>,
+                    Error = FrameworkError,
+                >,
+                FrameworkError,
+            >,
+        >,
+    ) {
+        let (id, capability, channelfuture) = self.serve::<Client>(StreamKind::OfferedService);
+
+        let sub = OfferedService {
+            id,
+            options: self.options,
+            capability,
+            _phantom: PhantomData,
+        };
+
+        (sub, channelfuture)
+    }
+
+    pub async fn connect_reverse_service<Client: ServiceClient>(
+        &self,
+        token: OfferedService<Client>,
+    ) -> Result<
+        impl Transport<ClientMessage<Client::Req>, Response<Client::Resp>, Error = FrameworkError>,
+        FrameworkError,
+    > {
+        let socks = self.redeem(token.id, token.capability.as_ref()).await?;
+        Ok(self.options.redeem(token.options).protocol(
+            socks,
+            self.counter::<Client>(StreamKind::OfferedService),
+            &self.router,
+        ))
+    }
+
+    /// The returned transport sends what the token's holder receives, and vice versa
+    pub fn accept_bistream<Rx: Serialize, Tx: DeserializeOwned>(
+        &self,
+    ) -> (
+        BiStream<Rx, Tx>,
+        impl Future<Output = Result<impl Transport<Rx, Tx, Error = FrameworkError>, FrameworkError>>,
+    ) {
+        let (id, capability, socks) = self.register();
+        let options = self.options;
+        let counter = self.counter::<(Rx, Tx)>(StreamKind::BiStream);
+        let router = self.router.clone();
+        let channelfuture = async move { Ok(options.protocol(socks.await?, counter, &router)) };
+
+        let sub = BiStream {
+            id,
+            options,
+            capability,
+            _phantom: PhantomData,
+        };
+
+        (sub, channelfuture)
+    }
+
+    pub async fn connect_bistream<Rx: DeserializeOwned, Tx: Serialize>(
+        &self,
+        token: BiStream<Rx, Tx>,
+    ) -> Result<impl Transport<Tx, Rx, Error = FrameworkError>, FrameworkError> {
+        let socks = self.redeem(token.id, token.capability.as_ref()).await?;
+        Ok(self.options.redeem(token.options).protocol(
+            socks,
+            self.counter::<(Rx, Tx)>(StreamKind::BiStream),
+            &self.router,
+        ))
+    }
+
+    /// Shut down the writing half to end the stream for the peer
+    pub fn accept_raw_stream(
+        &self,
+    ) -> (
+        RawStream,
+        impl Future<Output = Result<impl AsyncRead + AsyncWrite + Unpin, FrameworkError>>,
+    ) {
+        let (id, capability, socks) = self.register();
+        let streamfuture = async move { Ok(crate::io::webtransport_futures_bridge(socks.await?)) };
+
+        (RawStream { id, capability }, streamfuture)
+    }
+
+    /// Shut down the writing half to end the stream for the peer
+    pub async fn connect_raw_stream(
+        &self,
+        token: RawStream,
+    ) -> Result<impl AsyncRead + AsyncWrite + Unpin, FrameworkError> {
+        let socks = self.redeem(token.id, token.capability.as_ref()).await?;
+        Ok(crate::io::webtransport_futures_bridge(socks))
+    }
+
+    /// Drop the sink to end the stream
+    pub fn accept_downstream<T: Serialize>(
+        &self,
+    ) -> (
+        Downstream<T>,
+        impl Future<Output = Result<impl Sink<T, Error = FrameworkError> + Unpin, FrameworkError>>,
+    ) {
+        let (id, capability, verified) = self.register_uni();
+        let router = self.router.clone();
+        let options = self.options;
+        let counter = self.counter::<T>(StreamKind::Downstream);
+        let sinkfuture = async move {
+            verified.await?;
+            let tx = router.open_uni(StreamHeader::issued(id)).await?;
+            Ok(options.sender(tx, counter))
+        };
+
+        let token = Downstream {
+            id,
+            options,
+            capability,
+            _phantom: PhantomData,
+        };
+
+        (token, sinkfuture)
+    }
+
+    /// The stream ends once the issuer drops its sink
+    pub async fn connect_downstream<T: DeserializeOwned>(
+        &self,
+        token: Downstream<T>,
+    ) -> Result<impl Stream<Item = Result<T, FrameworkError>> + Unpin, FrameworkError> {
+        if token.capability.is_some() {
+            self.present_uni(token.id, token.capability.as_ref())
+                .await?;
+        }
+        let rx = self
+            .router
+            .wait_uni(StreamHeader::issued(token.id), &self.scope)
+            .await?;
+        let stream = self
+            .options
+            .redeem(token.options)
+            .receiver(rx, self.counter::<T>(StreamKind::Downstream));
+        Ok(Box::pin(self.router.until_closed(stream)))
+    }
+
+    /// Sends the results of `stream` to the token's holder. Resolves once the stream is sent,
+    /// or early if the holder drops its end, which drops `stream`.
+    pub fn accept_result_stream<T: Serialize, E: Serialize>(
+        &self,
+        stream: impl Stream<Item = Result<T, E>>,
+    ) -> (
+        ResultStream<T, E>,
+        impl Future<Output = Result<(), FrameworkError>>,
+    ) {
+        let (id, capability, socks) = self.register();
+        let options = self.options;
+        let counter = self.counter::<(T, E)>(StreamKind::ResultStream);
+        let sendfuture = async move {
+            let (tx, rx) = socks.await?;
+            crate::result_stream::send(stream, options.sender(tx, counter), rx).await
+        };
+
+        let token = ResultStream {
+            id,
+            options,
+            capability,
+            _phantom: PhantomData,
+        };
+
+        (token, sendfuture)
+    }
+
+    /// Ends after the issuer's first error. Drop the stream to cancel it.
+    pub async fn connect_result_stream<T: DeserializeOwned, E: DeserializeOwned>(
+        &self,
+        token: ResultStream<T, E>,
+    ) -> Result<impl Stream<Item = Result<T, ResultStreamError<E>>> + Unpin, FrameworkError> {
+        let (tx, rx) = self.redeem(token.id, token.capability.as_ref()).await?;
+        let frames = self
+            .options
+            .redeem(token.options)
+            .receiver(rx, self.counter::<(T, E)>(StreamKind::ResultStream));
+        Ok(crate::result_stream::receive(tx, frames))
+    }
+
+    /// The stream ends once the token's holder drops its sink
+    pub fn accept_upstream<T: DeserializeOwned>(
+        &self,
+    ) -> (
+        Upstream<T>,
+        impl Future<
+            Output = Result<impl Stream<Item = Result<T, FrameworkError>> + Unpin, FrameworkError>,
+        >,
+    ) {
+        let id = self.router.next_id();
+        let rx = self
+            .router
+            .wait_uni(StreamHeader::redeemed(id), &self.scope);
+        let signer = self.signer.clone();
+        let capability = signer.as_ref().map(|signer| signer.sign(id));
+        let router = self.router.clone();
+        let options = self.options;
+        let counter = self.counter::<T>(StreamKind::Upstream);
+        let streamfuture = async move {
+            let mut rx = rx.await?;
+            if let Some(signer) = signer {
+                signer.verify(id, &mut rx).await?;
+            }
+            let stream = options.receiver(rx, counter);
+            Ok(Box::pin(router.until_closed(stream)))
+        };
+
+        let token = Upstream {
+            id,
+            options,
+            capability,
+            _phantom: PhantomData,
+        };
+
+        (token, streamfuture)
+    }
+
+    /// Drop the sink to end the stream
+    pub async fn connect_upstream<T: Serialize>(
+        &self,
+        token: Upstream<T>,
+    ) -> Result<impl Sink<T, Error = FrameworkError> + Unpin, FrameworkError> {
+        let tx = self
+            .present_uni(token.id, token.capability.as_ref())
+            .await?;
+        Ok(self
+            .options
+            .redeem(token.options)
+            .sender(tx, self.counter::<T>(StreamKind::Upstream)))
+    }
+
+    /// The returned channel sends what the token's holder receives, and vice versa. If the
+    /// token carries a capability, the channel only receives once the holder has presented it.
+    pub fn accept_datagrams<Rx: Serialize, Tx: DeserializeOwned>(
+        &self,
+    ) -> (Datagrams<Rx, Tx>, DatagramChannel<Rx, Tx>) {
+        let (id, capability, verified) = self.register_uni();
+        let channel = if capability.is_some() {
+            self.datagrams
+                .channel_after(id, self.options.codec, verified)
+        } else {
+            self.datagrams.channel(id, true, self.options.codec)
+        };
+
+        let token = Datagrams {
+            id,
+            options: self.options,
+            capability,
+            _phantom: PhantomData,
+        };
+
+        (token, channel)
+    }
+
+    /// Presents the token's capability, if it has one, in the background
+    /// Warning: spawns tasks underneath
+    pub fn connect_datagrams<Rx: DeserializeOwned, Tx: Serialize>(
+        &self,
+        token: Datagrams<Rx, Tx>,
+    ) -> DatagramChannel<Tx, Rx> {
+        if let Some(capability) = token.capability {
+            let (inst, id) = (self.clone(), token.id);
+            crate::spawn(async move { inst.present_uni(id, Some(&capability)).await });
+        }
+        self.datagrams.channel(token.id, false, token.options.codec)
+    }
+}
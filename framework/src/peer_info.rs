@@ -0,0 +1,56 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+/// What the server knows about a client from the connection it came in on, captured when
+/// accepting it, e.g. by `webtransport::accept()` or `websocket::accept()`. Hand it to
+/// `ServerFramework::with_peer_info()` so that services can log, rate-limit or authorize by it.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    /// Unique among the sessions of this process
+    pub session_id: u64,
+    pub remote_addr: Option<SocketAddr>,
+    /// Path of the URL the client connected to
+    pub path: String,
+    pub query: Option<String>,
+    /// Headers of the request which opened the session. For WebTransport, these are only the
+    /// pseudo-headers of the CONNECT request, such as `:authority`, since web-transport-quinn
+    /// doesn't keep the others. WebSockets have them all.
+    pub headers: Vec<(String, String)>,
+    /// Application protocol negotiated by TLS, if any
+    pub alpn: Option<String>,
+    pub connected_at: SystemTime,
+}
+
+impl PeerInfo {
+    /// A new session, connected just now, about which nothing else is known yet
+    pub fn new() -> Self {
+        static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            remote_addr: None,
+            path: "/".into(),
+            query: None,
+            headers: vec![],
+            alpn: None,
+            connected_at: SystemTime::now(),
+        }
+    }
+
+    /// The value of the first header with this name, which is case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Default for PeerInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
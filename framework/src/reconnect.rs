@@ -0,0 +1,266 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    future::{select, Either},
+    Future,
+};
+use tokio::sync::watch;
+
+use crate::{ClientFramework, MaybeSend};
+
+/// How long `ReconnectingClient` waits between attempts to connect
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectOptions {
+    /// Wait after the first failure
+    pub initial_delay: Duration,
+    /// The wait doubles with each failure in a row, up to this
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What a `ReconnectingClient` is doing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Trying to connect. Attempts are counted from 1 since the last connection.
+    Connecting {
+        attempt: u32,
+    },
+    Connected,
+    /// The last attempt failed, or the connection was lost. Tries again after `retry_in`.
+    Disconnected {
+        error: String,
+        retry_in: Duration,
+    },
+}
+
+/// The live connection of a `ReconnectingClient`
+#[derive(Clone)]
+pub struct Connected<C> {
+    pub framework: ClientFramework,
+    /// The root client, created along with the connection
+    pub client: C,
+}
+
+/// Keeps a client connected, connecting again with exponential backoff whenever the session
+/// ends. The framework and root client are created anew for each connection, so get them from
+/// `current()` or `connected()` when needed instead of holding on to them. The connection is
+/// closed once every clone of this, and every receiver from `state()`, is dropped.
+#[derive(Clone)]
+pub struct ReconnectingClient<C> {
+    state: watch::Receiver<ConnectionState>,
+    current: Arc<Mutex<Option<Connected<C>>>>,
+}
+
+impl<C: Clone + MaybeSend + 'static> ReconnectingClient<C> {
+    /// `connect` is called for each attempt, and creates the session, the framework and the root
+    /// client, e.g. with `quic_session::client_session_selfsigned()`, `ClientFramework::new()`
+    /// and the client's `new()`, spawning its dispatch.
+    /// Warning: spawns tasks underneath
+    pub fn new<F, Fut, E>(mut connect: F, options: ReconnectOptions) -> Self
+    where
+        F: FnMut() -> Fut + MaybeSend + 'static,
+        Fut: Future<Output = Result<(ClientFramework, C), E>> + MaybeSend,
+        E: Display + MaybeSend,
+    {
+        let (state_tx, state) = watch::channel(ConnectionState::Connecting { attempt: 1 });
+        let current = Arc::new(Mutex::new(None));
+
+        let shared = current.clone();
+        crate::spawn(async move {
+            let mut attempt = 1;
+            let mut delay = options.initial_delay;
+
+            loop {
+                state_tx.send_replace(ConnectionState::Connecting { attempt });
+
+                let result = match select(Box::pin(connect()), Box::pin(state_tx.closed())).await {
+                    Either::Left((result, _)) => result,
+                    // Nobody is interested anymore
+                    Either::Right(_) => return,
+                };
+
+                let error = match result {
+                    Ok((framework, client)) => {
+                        *shared.lock().unwrap() = Some(Connected {
+                            framework: framework.clone(),
+                            client,
+                        });
+                        state_tx.send_replace(ConnectionState::Connected);
+                        attempt = 1;
+                        delay = options.initial_delay;
+
+                        let closed = Box::pin(framework.closed());
+                        let err = match select(closed, Box::pin(state_tx.closed())).await {
+                            Either::Left((err, _)) => err,
+                            Either::Right(_) => {
+                                framework.close(0, "client dropped").await;
+                                return;
+                            }
+                        };
+
+                        *shared.lock().unwrap() = None;
+                        err.to_string()
+                    }
+                    Err(e) => {
+                        attempt += 1;
+                        e.to_string()
+                    }
+                };
+
+                state_tx.send_replace(ConnectionState::Disconnected {
+                    error,
+                    retry_in: delay,
+                });
+
+                let sleep = Box::pin(crate::sleep(delay));
+                if let Either::Right(_) = select(sleep, Box::pin(state_tx.closed())).await {
+                    return;
+                }
+                delay = (delay * 2).min(options.max_delay);
+            }
+        });
+
+        Self { state, current }
+    }
+
+    /// Watches the state of the connection
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// The connection, if connected
+    pub fn current(&self) -> Option<Connected<C>> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Waits until connected, returning the connection
+    pub async fn connected(&self) -> Connected<C> {
+        // Cloned first, so that a connection made in between still counts as a change
+        let mut state = self.state.clone();
+        loop {
+            if let Some(connected) = self.current() {
+                return connected;
+            }
+            // The sender lives as long as we do
+            let _ = state.changed().await;
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::any::Any;
+
+    use super::*;
+    use crate::session::MemorySession;
+    use crate::ServerFramework;
+
+    /// The server ends of the connections made, with their root transports kept alive
+    type Servers = Arc<Mutex<Vec<(ServerFramework, Box<dyn Any + Send>)>>>;
+
+    const OPTIONS: ReconnectOptions = ReconnectOptions {
+        initial_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(40),
+    };
+
+    /// A client of servers in memory, which refuses the first `failures` attempts. Its root
+    /// client is the number of the attempt which connected.
+    fn reconnecting(failures: u32) -> (ReconnectingClient<u32>, Servers) {
+        let servers = Servers::default();
+        let shared = servers.clone();
+        let mut attempts = 0;
+        let connect = move || {
+            attempts += 1;
+            let attempt = attempts;
+            let servers = shared.clone();
+            async move {
+                if attempt <= failures {
+                    return Err("refused");
+                }
+                let (client_sess, server_sess) = MemorySession::pair();
+                let (client, server) = tokio::join!(
+                    ClientFramework::new::<u32, u32>(client_sess),
+                    ServerFramework::new::<u32, u32>(server_sess),
+                );
+                let (client, client_root) = client.unwrap();
+                let (server, server_root) = server.unwrap();
+                let roots = Box::new((client_root, server_root));
+                servers.lock().unwrap().push((server, roots));
+                Ok((client, attempt))
+            }
+        };
+        (ReconnectingClient::new(connect, OPTIONS), servers)
+    }
+
+    fn server(servers: &Servers, index: usize) -> ServerFramework {
+        servers.lock().unwrap()[index].0.clone()
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_back_off() {
+        let (client, _servers) = reconnecting(3);
+
+        // Each wait lasts long enough to be seen
+        let mut state = client.state();
+        let mut delays = vec![];
+        loop {
+            let current = state.borrow_and_update().clone();
+            match current {
+                ConnectionState::Connected => break,
+                ConnectionState::Disconnected { error, retry_in } => {
+                    assert_eq!(error, "refused");
+                    delays.push(retry_in);
+                }
+                ConnectionState::Connecting { .. } => {}
+            }
+            state.changed().await.unwrap();
+        }
+        let ms = Duration::from_millis;
+        assert_eq!(delays, [ms(20), ms(40), ms(40)]);
+        assert_eq!(client.current().unwrap().client, 4);
+    }
+
+    #[tokio::test]
+    async fn lost_connections_are_made_again() {
+        let (client, servers) = reconnecting(0);
+        assert_eq!(client.connected().await.client, 1);
+
+        server(&servers, 0).close(0, "restarting").await;
+
+        // The wait starts over after each connection
+        let mut state = client.state();
+        state
+            .wait_for(|s| {
+                matches!(s, ConnectionState::Disconnected { retry_in, .. }
+                    if *retry_in == OPTIONS.initial_delay)
+            })
+            .await
+            .unwrap();
+        assert!(client.current().is_none());
+        assert_eq!(client.connected().await.client, 2);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_client_closes_the_connection() {
+        let (client, servers) = reconnecting(0);
+        client.connected().await;
+
+        let server = server(&servers, 0);
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), server.closed())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+use futures::{
+    future::{select, Either},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::io::FrameworkError;
+use crate::session::{AnyRecvStream, AnySendStream, RecvStream};
+
+/// What goes over the wire for a `ResultStream`. The last frame is always `Error` or `End`, so
+/// the holder can tell a completed stream from one which was cut off.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum ResultFrame<T, E> {
+    Item(T),
+    Error(E),
+    End,
+}
+
+/// Why a `ResultStream` ended early
+#[derive(Debug, thiserror::Error)]
+pub enum ResultStreamError<E> {
+    /// The issuer's stream yielded this error, which ends it
+    #[error("{0}")]
+    Remote(E),
+
+    #[error(transparent)]
+    Framework(#[from] FrameworkError),
+}
+
+/// Sends the items of `stream` until it ends or yields an error. Stops early, dropping `stream`,
+/// once the holder drops its end.
+pub(crate) async fn send<T, E>(
+    stream: impl Stream<Item = Result<T, E>>,
+    mut sink: impl Sink<ResultFrame<T, E>, Error = FrameworkError> + Unpin,
+    mut rx: AnyRecvStream,
+) -> Result<(), FrameworkError> {
+    let pump = async move {
+        let mut stream = std::pin::pin!(stream);
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(item) => sink.send(ResultFrame::Item(item)).await?,
+                Err(e) => return sink.send(ResultFrame::Error(e)).await,
+            }
+        }
+        sink.send(ResultFrame::End).await
+    };
+
+    // The holder never writes, so its half only ends once it drops the stream
+    let cancelled = async move {
+        let _ = rx.read(1).await;
+    };
+
+    match select(std::pin::pin!(pump), std::pin::pin!(cancelled)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Ok(()),
+    }
+}
+
+/// Yields the items sent by `send()`, holding on to `tx` until the last one so that the issuer
+/// knows we're still listening
+pub(crate) fn receive<T, E>(
+    tx: AnySendStream,
+    frames: impl Stream<Item = Result<ResultFrame<T, E>, FrameworkError>> + Unpin,
+) -> impl Stream<Item = Result<T, ResultStreamError<E>>> + Unpin {
+    Box::pin(futures::stream::unfold(
+        Some((tx, frames)),
+        |state| async move {
+            let (tx, mut frames) = state?;
+            let err = match frames.next().await {
+                Some(Ok(ResultFrame::Item(item))) => return Some((Ok(item), Some((tx, frames)))),
+                Some(Ok(ResultFrame::Error(e))) => ResultStreamError::Remote(e),
+                Some(Ok(ResultFrame::End)) => return None,
+                Some(Err(e)) => e.into(),
+                None => FrameworkError::StreamIncomplete.into(),
+            };
+            Some((Err(err), None))
+        },
+    ))
+}
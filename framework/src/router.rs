@@ -0,0 +1,769 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    channel::oneshot,
+    future::{pending, ready, select, Either, Shared},
+    Future, Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::io::{
+    decode, encode, read_stream_header, read_to_end, write_all, write_stream_header,
+    FrameworkError, CALL_CANCELLED_CODE, STREAM_REFUSED_CODE,
+};
+use crate::session::{AnyRecvStream, AnySendStream, AnySession, RecvStream, Session};
+
+/// Identity of a stream, as written in the header of each stream opened for a token
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StreamId(pub(crate) u64);
+
+/// The root transport always uses this id, tokens are numbered after it
+pub(crate) const ROOT_STREAM: StreamId = StreamId(0);
+
+/// Reserved for the stream which carries the reason the session is being closed
+const CLOSE_STREAM: StreamId = StreamId(u64::MAX);
+
+/// Longest close message we will read from the peer
+const MAX_CLOSE_MESSAGE_SIZE: usize = 1024;
+
+/// Longest close reason we send, leaving room for the code and the length in the message
+const MAX_CLOSE_REASON_LEN: usize =
+    MAX_CLOSE_MESSAGE_SIZE - std::mem::size_of::<u32>() - std::mem::size_of::<u64>();
+
+/// How long to wait for the peer to hang up after telling it we are closing the session
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long to wait for the peer to open the stream for a token, by default
+pub const DEFAULT_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most streams the issuer of tokens we hold may open before we redeem them, in each
+/// direction. Streams past this are stopped with `STREAM_REFUSED_CODE`.
+const MAX_HELD_STREAMS: usize = 256;
+
+/// Precedes the data on each stream (and each datagram)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct StreamHeader {
+    pub id: StreamId,
+    /// Whether the stream was opened by the side which issued the token. Both sides allocate
+    /// ids, so this tells the receiver whose id it is.
+    pub from_issuer: bool,
+}
+
+impl StreamHeader {
+    /// Header for a stream opened by the holder of a token
+    pub fn redeemed(id: StreamId) -> Self {
+        Self {
+            id,
+            from_issuer: false,
+        }
+    }
+
+    /// Header for a stream opened by the side which handed out the token
+    pub fn issued(id: StreamId) -> Self {
+        Self {
+            id,
+            from_issuer: true,
+        }
+    }
+}
+
+type Socks = (AnySendStream, AnyRecvStream);
+
+/// Bounds the waits for the streams of tokens issued while handling an RPC call
+#[derive(Clone, Default)]
+pub(crate) struct CallScope {
+    /// Never set in the browser, where `Instant::now()` panics
+    pub deadline: Option<Instant>,
+    /// Fails if the call is cancelled, and succeeds once it has returned
+    pub done: Option<Shared<oneshot::Receiver<()>>>,
+}
+
+/// Routes incoming streams to the futures waiting on them, by the id in the stream's header.
+/// This means that the order in which streams are opened does not matter.
+#[derive(Clone)]
+pub(crate) struct StreamRouter {
+    sess: AnySession,
+    state: Arc<Mutex<RouterState>>,
+}
+
+struct RouterState {
+    next_id: u64,
+    bi: Slots<Socks>,
+    uni: Slots<AnyRecvStream>,
+    /// Set once the session stops accepting streams
+    closed: bool,
+    /// Code and reason given by whichever side closed the session with `close()`
+    close_reason: Option<(u32, String)>,
+    /// Error the session ended with, if it wasn't closed with `close()`
+    failure: Option<String>,
+    /// Woken up when the session ends
+    listeners: Vec<oneshot::Sender<()>>,
+    accept_timeout: Option<Duration>,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Bi,
+    Uni,
+}
+
+impl RouterState {
+    fn forget(&mut self, direction: Direction, header: &StreamHeader) {
+        match direction {
+            Direction::Bi => self.bi.forget(header),
+            Direction::Uni => self.uni.forget(header),
+        }
+    }
+
+    fn cancel(&mut self, direction: Direction, header: StreamHeader) {
+        match direction {
+            Direction::Bi => self.bi.cancel(header),
+            Direction::Uni => self.uni.cancel(header),
+        }
+    }
+
+    fn expire(&mut self, direction: Direction, header: &StreamHeader) {
+        match direction {
+            Direction::Bi => self.bi.expire(header),
+            Direction::Uni => self.uni.expire(header),
+        }
+    }
+}
+
+enum Slot<T> {
+    /// Someone is waiting on the stream
+    Waiting(oneshot::Sender<Result<T, FrameworkError>>),
+    /// The stream arrived before anyone was waiting on it, as the numbered arrival
+    Arrived(T, u64),
+    /// The call which issued the token was cancelled, so the stream is reset if it arrives
+    /// before the slot expires
+    Cancelled,
+}
+
+/// What became of a stream the peer opened
+enum Arrival<T> {
+    /// Handed to whoever was waiting on it, or dropped for an unknown id
+    Routed,
+    /// Held until its token is redeemed, as the numbered arrival, which expires
+    Held(u64),
+    /// To be stopped with the given code
+    Refused(T, u32),
+}
+
+struct Slots<T> {
+    slots: HashMap<StreamHeader, Slot<T>>,
+    /// Number of `Slot::Arrived`
+    held: usize,
+    next_arrival: u64,
+}
+
+impl<T> Slots<T> {
+    fn wait(
+        &mut self,
+        header: StreamHeader,
+        closed: bool,
+    ) -> oneshot::Receiver<Result<T, FrameworkError>> {
+        let (tx, rx) = oneshot::channel();
+        match self.take(&header) {
+            Some(Slot::Arrived(stream, _)) => {
+                let _ = tx.send(Ok(stream));
+            }
+            _ if closed => (),
+            _ => {
+                self.slots.insert(header, Slot::Waiting(tx));
+            }
+        }
+        rx
+    }
+
+    /// Removes a slot, keeping count of the held streams
+    fn take(&mut self, header: &StreamHeader) -> Option<Slot<T>> {
+        let slot = self.slots.remove(header);
+        if let Some(Slot::Arrived(..)) = slot {
+            self.held -= 1;
+        }
+        slot
+    }
+
+    /// Nobody is waiting on the stream anymore. Cancelled slots are kept until they expire,
+    /// since the peer may still open the stream.
+    fn forget(&mut self, header: &StreamHeader) {
+        if !matches!(self.slots.get(header), Some(Slot::Cancelled)) {
+            self.take(header);
+        }
+    }
+
+    fn cancel(&mut self, header: StreamHeader) {
+        self.take(&header);
+        self.slots.insert(header, Slot::Cancelled);
+    }
+
+    /// Drops a cancelled slot whose stream never arrived
+    fn expire(&mut self, header: &StreamHeader) {
+        if matches!(self.slots.get(header), Some(Slot::Cancelled)) {
+            self.slots.remove(header);
+        }
+    }
+
+    /// Takes back a held stream whose token wasn't redeemed in time
+    fn expire_held(&mut self, header: &StreamHeader, arrival: u64) -> Option<T> {
+        match self.slots.get(header) {
+            Some(Slot::Arrived(_, n)) if *n == arrival => match self.take(header) {
+                Some(Slot::Arrived(stream, _)) => Some(stream),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Hands the stream to whoever is waiting on it, or holds it until someone does
+    fn arrive(&mut self, header: StreamHeader, stream: T) -> Arrival<T> {
+        match self.take(&header) {
+            Some(Slot::Waiting(tx)) => {
+                let _ = tx.send(Ok(stream));
+            }
+            Some(Slot::Cancelled) => return Arrival::Refused(stream, CALL_CANCELLED_CODE),
+            // The issuer may open a stream before its token has been redeemed on our side, but
+            // only so many, so that a peer can't fill our memory with made up ids
+            _ if header.from_issuer => {
+                if self.held >= MAX_HELD_STREAMS {
+                    return Arrival::Refused(stream, STREAM_REFUSED_CODE);
+                }
+                let arrival = self.next_arrival;
+                self.next_arrival += 1;
+                self.held += 1;
+                self.slots.insert(header, Slot::Arrived(stream, arrival));
+                return Arrival::Held(arrival);
+            }
+            // Streams with unknown ids are dropped
+            _ => (),
+        }
+        Arrival::Routed
+    }
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Self {
+            slots: HashMap::new(),
+            held: 0,
+            next_arrival: 0,
+        }
+    }
+}
+
+impl StreamRouter {
+    /// Creates a new router. Call `run()` to start routing streams.
+    pub fn new(sess: AnySession) -> Self {
+        Self {
+            sess,
+            state: Arc::new(Mutex::new(RouterState {
+                next_id: ROOT_STREAM.0 + 1,
+                bi: Slots::default(),
+                uni: Slots::default(),
+                closed: false,
+                close_reason: None,
+                failure: None,
+                listeners: vec![],
+                accept_timeout: Some(DEFAULT_ACCEPT_TIMEOUT),
+            })),
+        }
+    }
+
+    /// Accepts streams on the session until it closes
+    /// Warning: spawns tasks underneath
+    pub fn run(&self) {
+        let router = self.clone();
+        let bi_sess = self.sess.clone();
+        crate::spawn(async move {
+            let err = loop {
+                let (tx, mut rx) = match bi_sess.accept_bi().await {
+                    Ok(socks) => socks,
+                    Err(e) => break e,
+                };
+
+                // Read the header separately, so that a slow peer can't hold up other streams
+                let router = router.clone();
+                crate::spawn(async move {
+                    let header = read_header(&mut rx, router.expiry()).await?;
+                    let arrival = router.state.lock().unwrap().bi.arrive(header, (tx, rx));
+                    match arrival {
+                        Arrival::Routed => (),
+                        Arrival::Held(arrival) => {
+                            router.expire_held(Direction::Bi, header, arrival)
+                        }
+                        Arrival::Refused((_, mut rx), code) => rx.stop(code),
+                    }
+                    Ok::<_, FrameworkError>(())
+                });
+            };
+
+            router.ended(err.to_string());
+            Err::<(), _>(err)
+        });
+
+        let router = self.clone();
+        let uni_sess = self.sess.clone();
+        crate::spawn(async move {
+            let err = loop {
+                let mut rx = match uni_sess.accept_uni().await {
+                    Ok(rx) => rx,
+                    Err(e) => break e,
+                };
+
+                let router = router.clone();
+                crate::spawn(async move {
+                    let header = read_header(&mut rx, router.expiry()).await?;
+
+                    if header.id == CLOSE_STREAM {
+                        let msg = read_to_end(&mut rx, MAX_CLOSE_MESSAGE_SIZE).await?;
+                        let (code, reason): (u32, String) = decode(&msg)?;
+                        router.set_close_reason(code, &reason);
+                        router.sess.close(code, &reason);
+                        return Ok(());
+                    }
+
+                    let arrival = router.state.lock().unwrap().uni.arrive(header, rx);
+                    match arrival {
+                        Arrival::Routed => (),
+                        Arrival::Held(arrival) => {
+                            router.expire_held(Direction::Uni, header, arrival)
+                        }
+                        Arrival::Refused(mut rx, code) => rx.stop(code),
+                    }
+
+                    Ok::<_, FrameworkError>(())
+                });
+            };
+
+            router.ended(err.to_string());
+            Err::<(), _>(err)
+        });
+    }
+
+    /// Wakes up everyone still waiting, there won't be any more streams
+    fn ended(&self, failure: String) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.failure.get_or_insert(failure);
+        state.bi = Slots::default();
+        state.uni = Slots::default();
+        for listener in state.listeners.drain(..) {
+            let _ = listener.send(());
+        }
+    }
+
+    /// Only the first reason counts, in case both sides close at once
+    fn set_close_reason(&self, code: u32, reason: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .close_reason
+            .get_or_insert_with(|| (code, reason.to_string()));
+    }
+
+    /// Why the session ended, or `StreamNotConnected` if we can't tell
+    pub fn end_error(&self) -> FrameworkError {
+        let state = self.state.lock().unwrap();
+        if let Some((code, reason)) = &state.close_reason {
+            FrameworkError::Closed {
+                code: *code,
+                reason: reason.clone(),
+            }
+        } else if let Some(failure) = &state.failure {
+            FrameworkError::Transport(failure.clone())
+        } else {
+            FrameworkError::StreamNotConnected
+        }
+    }
+
+    /// Whether the session was closed on purpose by either side
+    pub fn closed_gracefully(&self) -> bool {
+        self.state.lock().unwrap().close_reason.is_some()
+    }
+
+    /// Resolves once the session has ended
+    pub fn closed(&self) -> impl Future<Output = ()> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            let _ = tx.send(());
+        } else {
+            state.listeners.push(tx);
+        }
+        async move {
+            let _ = rx.await;
+        }
+    }
+
+    /// Tells the peer why we are closing the session, gives it a moment to hang up, then closes
+    /// the session. Reasons longer than the peer reads are cut short.
+    pub async fn close(&self, code: u32, reason: &str) {
+        let reason = truncate_reason(reason);
+        self.set_close_reason(code, reason);
+
+        if let Ok(mut tx) = self.open_uni(StreamHeader::redeemed(CLOSE_STREAM)).await {
+            if let Ok(msg) = encode(&(code, reason)) {
+                let _ = write_all(&mut tx, &msg).await;
+            }
+            drop(tx);
+
+            let closed = Box::pin(self.closed());
+            let _ = select(closed, Box::pin(crate::sleep(CLOSE_GRACE_PERIOD))).await;
+        }
+
+        self.sess.close(code, reason);
+    }
+
+    /// Sets how long to wait for the peer to open the stream for a token. `None` waits forever.
+    pub fn set_accept_timeout(&self, timeout: Option<Duration>) {
+        self.state.lock().unwrap().accept_timeout = timeout;
+    }
+
+    /// Reserves a new id
+    pub fn next_id(&self) -> StreamId {
+        let mut state = self.state.lock().unwrap();
+        let id = StreamId(state.next_id);
+        state.next_id += 1;
+        id
+    }
+
+    /// Reserves a new stream id, returning a future which resolves once the peer opens a
+    /// bidirectional stream with that id
+    pub fn register(
+        &self,
+        scope: &CallScope,
+    ) -> (
+        StreamId,
+        impl Future<Output = Result<Socks, FrameworkError>>,
+    ) {
+        let id = self.next_id();
+        (id, self.wait_bi(StreamHeader::redeemed(id), scope))
+    }
+
+    /// Waits for the root stream
+    pub fn register_root(&self) -> impl Future<Output = Result<Socks, FrameworkError>> {
+        self.wait_bi(StreamHeader::redeemed(ROOT_STREAM), &CallScope::default())
+    }
+
+    fn wait_bi(
+        &self,
+        header: StreamHeader,
+        scope: &CallScope,
+    ) -> impl Future<Output = Result<Socks, FrameworkError>> {
+        let mut state = self.state.lock().unwrap();
+        let closed = state.closed;
+        let rx = state.bi.wait(header, closed);
+        self.wait(
+            rx,
+            Direction::Bi,
+            header,
+            state.accept_timeout,
+            scope.clone(),
+        )
+    }
+
+    /// Waits for the peer to open a unidirectional stream with the given header
+    pub fn wait_uni(
+        &self,
+        header: StreamHeader,
+        scope: &CallScope,
+    ) -> impl Future<Output = Result<AnyRecvStream, FrameworkError>> {
+        let mut state = self.state.lock().unwrap();
+        let closed = state.closed;
+        let rx = state.uni.wait(header, closed);
+        self.wait(
+            rx,
+            Direction::Uni,
+            header,
+            state.accept_timeout,
+            scope.clone(),
+        )
+    }
+
+    /// Ends the stream when the session is closed on purpose, instead of yielding the error
+    pub fn until_closed<S, T>(&self, stream: S) -> impl Stream<Item = Result<T, FrameworkError>>
+    where
+        S: Stream<Item = Result<T, FrameworkError>>,
+    {
+        let router = self.clone();
+        stream.take_while(move |item| ready(item.is_ok() || !router.closed_gracefully()))
+    }
+
+    /// Opens a stream to the peer, identified by the given id
+    pub async fn open_bi(&self, id: StreamId) -> Result<Socks, FrameworkError> {
+        let (mut tx, rx) = self
+            .sess
+            .open_bi()
+            .await
+            .map_err(|e| self.session_error(e))?;
+        write_stream_header(&mut tx, StreamHeader::redeemed(id)).await?;
+        Ok((tx, rx))
+    }
+
+    /// Opens a unidirectional stream to the peer, identified by the given header
+    pub async fn open_uni(&self, header: StreamHeader) -> Result<AnySendStream, FrameworkError> {
+        let mut tx = self
+            .sess
+            .open_uni()
+            .await
+            .map_err(|e| self.session_error(e))?;
+        write_stream_header(&mut tx, header).await?;
+        Ok(tx)
+    }
+
+    /// Reports why the session was closed, if it was closed on purpose
+    fn session_error(&self, err: FrameworkError) -> FrameworkError {
+        if self.closed_gracefully() {
+            self.end_error()
+        } else {
+            err
+        }
+    }
+
+    fn wait<T>(
+        &self,
+        rx: oneshot::Receiver<Result<T, FrameworkError>>,
+        direction: Direction,
+        header: StreamHeader,
+        timeout: Option<Duration>,
+        scope: CallScope,
+    ) -> impl Future<Output = Result<T, FrameworkError>> {
+        // Nobody is waiting on the stream anymore once this future is finished or dropped
+        let guard = SlotGuard {
+            router: self.clone(),
+            direction,
+            header,
+        };
+
+        async move {
+            // Whichever comes first of the accept timeout and the call's deadline
+            let remaining = scope
+                .deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let expired = async move {
+                match (timeout, remaining) {
+                    (Some(timeout), Some(remaining)) if timeout < remaining => {
+                        crate::sleep(timeout).await;
+                        FrameworkError::ConnectTimeout(timeout)
+                    }
+                    (_, Some(remaining)) => {
+                        crate::sleep(remaining).await;
+                        FrameworkError::DeadlineExceeded
+                    }
+                    (Some(timeout), None) => {
+                        crate::sleep(timeout).await;
+                        FrameworkError::ConnectTimeout(timeout)
+                    }
+                    (None, None) => pending().await,
+                }
+            };
+            let cancelled = async move {
+                if let Some(done) = scope.done {
+                    // The call dropped its end without returning
+                    if done.await.is_err() {
+                        return FrameworkError::Cancelled;
+                    }
+                }
+                pending().await
+            };
+
+            let ended = select(Box::pin(expired), Box::pin(cancelled));
+            let result = match select(rx, ended).await {
+                Either::Left((result, _)) => result,
+                Either::Right((ended, _)) => {
+                    let (err, _) = ended.factor_first();
+                    if let FrameworkError::Cancelled = err {
+                        guard.router.cancel(guard.direction, guard.header);
+                    }
+                    return Err(err);
+                }
+            };
+
+            // The slot is only dropped without an answer when the session ends
+            result.map_err(|_| guard.router.end_error())?
+        }
+    }
+
+    /// How long the peer gets to open a stream, or to send its header. Also applies when
+    /// waiting forever for tokens, so that what the peer leaves behind doesn't pile up.
+    fn expiry(&self) -> Duration {
+        self.state
+            .lock()
+            .unwrap()
+            .accept_timeout
+            .unwrap_or(DEFAULT_ACCEPT_TIMEOUT)
+    }
+
+    /// Marks the slot of a cancelled call's stream, so that the stream is reset if the peer
+    /// opens it anyway. The slot is removed once the peer has had as long as it gets to open
+    /// a stream, so that cancelled calls don't pile up for the rest of the session.
+    fn cancel(&self, direction: Direction, header: StreamHeader) {
+        self.state.lock().unwrap().cancel(direction, header);
+
+        let router = self.clone();
+        let expiry = self.expiry();
+        crate::spawn(async move {
+            crate::sleep(expiry).await;
+            router.state.lock().unwrap().expire(direction, &header);
+        });
+    }
+
+    /// Stops a stream the peer opened ahead of our redeeming its token, unless the token is
+    /// redeemed within `expiry()`, so that such streams don't pile up for the rest of the
+    /// session
+    fn expire_held(&self, direction: Direction, header: StreamHeader, arrival: u64) {
+        let router = self.clone();
+        let expiry = self.expiry();
+        crate::spawn(async move {
+            crate::sleep(expiry).await;
+            let mut state = router.state.lock().unwrap();
+            match direction {
+                Direction::Bi => {
+                    if let Some((_, mut rx)) = state.bi.expire_held(&header, arrival) {
+                        rx.stop(STREAM_REFUSED_CODE);
+                    }
+                }
+                Direction::Uni => {
+                    if let Some(mut rx) = state.uni.expire_held(&header, arrival) {
+                        rx.stop(STREAM_REFUSED_CODE);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Reads the header of a stream the peer opened. A stream whose header doesn't arrive within
+/// `expiry` is stopped, so that it doesn't hold its task forever.
+async fn read_header(
+    rx: &mut AnyRecvStream,
+    expiry: Duration,
+) -> Result<StreamHeader, FrameworkError> {
+    let read = Box::pin(read_stream_header(rx));
+    let header = match select(read, Box::pin(crate::sleep(expiry))).await {
+        Either::Left((header, _)) => Some(header),
+        Either::Right(_) => None,
+    };
+    header.unwrap_or_else(|| {
+        rx.stop(STREAM_REFUSED_CODE);
+        Err(FrameworkError::ConnectTimeout(expiry))
+    })
+}
+
+/// Cuts a close reason down to `MAX_CLOSE_REASON_LEN` bytes, on a character boundary
+fn truncate_reason(reason: &str) -> &str {
+    if reason.len() <= MAX_CLOSE_REASON_LEN {
+        return reason;
+    }
+    let mut end = MAX_CLOSE_REASON_LEN;
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    &reason[..end]
+}
+
+/// Removes the slot of a stream when nobody is waiting on it anymore
+struct SlotGuard {
+    router: StreamRouter,
+    direction: Direction,
+    header: StreamHeader,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let mut state = self.router.state.lock().unwrap();
+        state.forget(self.direction, &self.header);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::session::{MemorySession, SendStream};
+
+    /// A running router on one end of a session, and the other end to open streams from
+    fn router(accept_timeout: Duration) -> (StreamRouter, MemorySession) {
+        let (ours, theirs) = MemorySession::pair();
+        let router = StreamRouter::new(ours.into());
+        router.set_accept_timeout(Some(accept_timeout));
+        router.run();
+        (router, theirs)
+    }
+
+    async fn open(peer: &MemorySession, header: Option<StreamHeader>) -> AnySendStream {
+        let mut tx: AnySendStream = peer.open_uni().await.unwrap().into();
+        if let Some(header) = header {
+            write_stream_header(&mut tx, header).await.unwrap();
+        }
+        tx
+    }
+
+    /// Waits until the stream has been stopped, returning the code
+    async fn stopped(tx: &mut AnySendStream) -> u32 {
+        loop {
+            match tx.write(b"x").await {
+                Ok(_) => crate::sleep(Duration::from_millis(5)).await,
+                Err(FrameworkError::StreamReset { code }) => return code,
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    fn held(router: &StreamRouter) -> usize {
+        router.state.lock().unwrap().uni.held
+    }
+
+    #[tokio::test]
+    async fn streams_ahead_of_their_token_expire() {
+        let (router, peer) = router(Duration::from_millis(50));
+
+        let mut tx = open(&peer, Some(StreamHeader::issued(StreamId(7)))).await;
+        assert_eq!(stopped(&mut tx).await, STREAM_REFUSED_CODE);
+        assert_eq!(held(&router), 0);
+    }
+
+    #[tokio::test]
+    async fn streams_ahead_of_their_token_are_capped() {
+        let (router, peer) = router(DEFAULT_ACCEPT_TIMEOUT);
+
+        let mut streams = vec![];
+        for id in 0..MAX_HELD_STREAMS as u64 {
+            streams.push(open(&peer, Some(StreamHeader::issued(StreamId(id)))).await);
+        }
+        while held(&router) < MAX_HELD_STREAMS {
+            crate::sleep(Duration::from_millis(5)).await;
+        }
+
+        let mut extra = open(&peer, Some(StreamHeader::issued(StreamId(1000)))).await;
+        assert_eq!(stopped(&mut extra).await, STREAM_REFUSED_CODE);
+        assert_eq!(held(&router), MAX_HELD_STREAMS);
+
+        // Those which were held are still handed over once their token is redeemed
+        let header = StreamHeader::issued(StreamId(3));
+        router
+            .wait_uni(header, &CallScope::default())
+            .await
+            .unwrap();
+        assert_eq!(held(&router), MAX_HELD_STREAMS - 1);
+    }
+
+    #[tokio::test]
+    async fn streams_without_a_header_are_stopped() {
+        let (_router, peer) = router(Duration::from_millis(50));
+
+        // Writing would complete the header, so only check once it should have been stopped
+        let mut tx = open(&peer, None).await;
+        crate::sleep(Duration::from_millis(200)).await;
+        assert!(matches!(
+            tx.write(b"x").await,
+            Err(FrameworkError::StreamReset {
+                code: STREAM_REFUSED_CODE
+            })
+        ));
+    }
+}
@@ -0,0 +1,605 @@
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    fmt::Write,
+    sync::{Mutex, OnceLock},
+};
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+
+/// Deepest nesting traced before giving up, which also stops recursive types
+const MAX_DEPTH: usize = 64;
+
+/// Most enum variants explored, in total
+const MAX_PASSES: usize = 4096;
+
+/// Hashes the shape of a type: its structs and their fields, its enums and their variants, and
+/// so on down to the primitives. Peers built from different versions of a type get different
+/// fingerprints, while the names of the modules it lives in don't matter. Traced once per type
+/// and process.
+///
+/// The shape is found by deserializing the type from a tracer, once per enum variant. Types
+/// whose `Deserialize` needs a self-describing format, or rejects the zeroes and empty strings
+/// the tracer hands it, can't be traced all the way. For those, the shape ends at the part
+/// which failed, with the error in its place, so parts after it aren't covered. That way the
+/// fingerprint still only depends on the names serde sees, never on the compiler's type names.
+pub(crate) fn fingerprint<T: DeserializeOwned + 'static>() -> u64 {
+    static CACHE: OnceLock<Mutex<HashMap<TypeId, u64>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+
+    if let Some(&fingerprint) = cache.lock().unwrap().get(&TypeId::of::<T>()) {
+        return fingerprint;
+    }
+    // Traced without the lock, since a type can take a while
+    let fingerprint = fnv1a(trace::<T>().as_bytes());
+    cache.lock().unwrap().insert(TypeId::of::<T>(), fingerprint);
+    fingerprint
+}
+
+fn trace<T: DeserializeOwned>() -> String {
+    let mut tracer = Tracer::default();
+    let mut shape = String::new();
+
+    for _ in 0..MAX_PASSES {
+        let result = T::deserialize(&mut tracer);
+        shape.push_str(&tracer.out);
+        if let Err(e) = result {
+            write!(shape, "!{e}").unwrap();
+            return shape;
+        }
+        shape.push('\n');
+        tracer.out.clear();
+
+        // Explore the most recently found enum first, so that enums nested in one variant are
+        // covered while that variant is still selected
+        let Some(next) = tracer
+            .enums
+            .iter_mut()
+            .rev()
+            .find(|e| e.choice + 1 < e.variants)
+        else {
+            return shape;
+        };
+        next.choice += 1;
+    }
+
+    shape.push_str("!too many enum variants");
+    shape
+}
+
+/// 64 bit FNV-1a, which unlike `DefaultHasher` is the same everywhere
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug)]
+struct TraceError(String);
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+struct EnumChoice {
+    name: &'static str,
+    variants: usize,
+    choice: usize,
+}
+
+#[derive(Default)]
+struct Tracer {
+    /// Description of everything deserialized so far in this pass
+    out: String,
+    /// Every enum seen, in the order they were first found, and the variant to pick
+    enums: Vec<EnumChoice>,
+    /// Named types being deserialized right now
+    stack: Vec<&'static str>,
+    /// How many of those are nested in themselves. Within them, sequences and maps are empty
+    /// and options are `None`, which is what ends most recursive types.
+    recursing: usize,
+    depth: usize,
+}
+
+impl Tracer {
+    fn enter(&mut self) -> Result<(), TraceError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(TraceError("type is too deep, or recursive".into()));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// Enters a named type, which must be left with `leave_named()`
+    fn enter_named(&mut self, name: &'static str) -> Result<(), TraceError> {
+        self.enter()?;
+        if self.stack.contains(&name) {
+            self.recursing += 1;
+        }
+        self.stack.push(name);
+        Ok(())
+    }
+
+    fn leave_named(&mut self) {
+        if let Some(name) = self.stack.pop() {
+            if self.stack.contains(&name) {
+                self.recursing -= 1;
+            }
+        }
+        self.leave();
+    }
+
+    /// How many elements to hand out of a sequence or map
+    fn elements(&self) -> usize {
+        if self.recursing > 0 {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn choose(&mut self, name: &'static str, variants: usize) -> usize {
+        // Nested occurrences of the same enum take the first variant, which is usually the one
+        // that ends the recursion
+        if self.stack.contains(&name) {
+            return 0;
+        }
+
+        match self.enums.iter().find(|e| e.name == name) {
+            Some(e) => e.choice,
+            None => {
+                self.enums.push(EnumChoice {
+                    name,
+                    variants,
+                    choice: 0,
+                });
+                0
+            }
+        }
+    }
+}
+
+macro_rules! trace_primitive {
+    ($($method:ident => $visit:ident($value:expr),)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+                self.out.push_str(stringify!($visit));
+                self.out.push(' ');
+                visitor.$visit($value)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut Tracer {
+    type Error = TraceError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError> {
+        Err(TraceError("self-describing types can't be traced".into()))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        visitor.visit_unit()
+    }
+
+    trace_primitive! {
+        deserialize_bool => visit_bool(false),
+        deserialize_i8 => visit_i8(0),
+        deserialize_i16 => visit_i16(0),
+        deserialize_i32 => visit_i32(0),
+        deserialize_i64 => visit_i64(0),
+        deserialize_i128 => visit_i128(0),
+        deserialize_u8 => visit_u8(0),
+        deserialize_u16 => visit_u16(0),
+        deserialize_u32 => visit_u32(0),
+        deserialize_u64 => visit_u64(0),
+        deserialize_u128 => visit_u128(0),
+        deserialize_f32 => visit_f32(0.0),
+        deserialize_f64 => visit_f64(0.0),
+        deserialize_char => visit_char('\0'),
+        deserialize_str => visit_str(""),
+        deserialize_string => visit_str(""),
+        deserialize_bytes => visit_bytes(&[]),
+        deserialize_byte_buf => visit_bytes(&[]),
+        deserialize_identifier => visit_str(""),
+        deserialize_unit => visit_unit(),
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        if self.recursing > 0 {
+            self.out.push_str("option<> ");
+            return visitor.visit_none();
+        }
+        self.enter()?;
+        self.out.push_str("option<");
+        let value = visitor.visit_some(&mut *self)?;
+        self.out.push_str("> ");
+        self.leave();
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        write!(self.out, "{name} ").unwrap();
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.enter_named(name)?;
+        write!(self.out, "{name}(").unwrap();
+        let value = visitor.visit_newtype_struct(&mut *self)?;
+        self.out.push_str(") ");
+        self.leave_named();
+        Ok(value)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.enter()?;
+        self.out.push_str("seq[");
+        let len = self.elements();
+        let value = visitor.visit_seq(Elements::new(self, len, None))?;
+        self.out.push_str("] ");
+        self.leave();
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.enter()?;
+        self.out.push('(');
+        let value = visitor.visit_seq(Elements::new(self, len, None))?;
+        self.out.push_str(") ");
+        self.leave();
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.enter_named(name)?;
+        write!(self.out, "{name}(").unwrap();
+        let value = visitor.visit_seq(Elements::new(self, len, None))?;
+        self.out.push_str(") ");
+        self.leave_named();
+        Ok(value)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.enter()?;
+        self.out.push_str("map{");
+        let remaining = self.elements();
+        let value = visitor.visit_map(Entry {
+            tracer: self,
+            remaining,
+        })?;
+        self.out.push_str("} ");
+        self.leave();
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.enter_named(name)?;
+        write!(self.out, "{name}{{").unwrap();
+        let value = visitor.visit_seq(Elements::new(self, fields.len(), Some(fields)))?;
+        self.out.push_str("} ");
+        self.leave_named();
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        write!(self.out, "{name}{variants:?}::").unwrap();
+
+        let choice = self.choose(name, variants.len());
+        self.out
+            .push_str(variants.get(choice).copied().unwrap_or_default());
+
+        self.enter_named(name)?;
+        let value = visitor.visit_enum(Variant {
+            tracer: &mut *self,
+            index: choice as u32,
+        })?;
+        self.leave_named();
+
+        self.out.push(' ');
+        Ok(value)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Hands out the elements of a sequence, tuple or struct
+struct Elements<'a> {
+    tracer: &'a mut Tracer,
+    remaining: usize,
+    fields: Option<&'static [&'static str]>,
+    index: usize,
+}
+
+impl<'a> Elements<'a> {
+    fn new(tracer: &'a mut Tracer, len: usize, fields: Option<&'static [&'static str]>) -> Self {
+        Self {
+            tracer,
+            remaining: len,
+            fields,
+            index: 0,
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for Elements<'_> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        if let Some(field) = self.fields.and_then(|fields| fields.get(self.index)) {
+            write!(self.tracer.out, "{field}: ").unwrap();
+        }
+        self.index += 1;
+
+        seed.deserialize(&mut *self.tracer).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// Hands out a single entry of a map
+struct Entry<'a> {
+    tracer: &'a mut Tracer,
+    remaining: usize,
+}
+
+impl<'de> MapAccess<'de> for Entry<'_> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.tracer).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, TraceError> {
+        self.tracer.out.push_str("=> ");
+        seed.deserialize(&mut *self.tracer)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// Picks the enum variant chosen for this pass
+struct Variant<'a> {
+    tracer: &'a mut Tracer,
+    index: u32,
+}
+
+impl<'de> EnumAccess<'de> for Variant<'_> {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), TraceError> {
+        let index: de::value::U32Deserializer<TraceError> = self.index.into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant<'_> {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, TraceError> {
+        self.tracer.out.push('(');
+        let value = seed.deserialize(&mut *self.tracer)?;
+        self.tracer.out.push(')');
+        Ok(value)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.tracer.out.push('(');
+        let value = visitor.visit_seq(Elements::new(&mut *self.tracer, len, None))?;
+        self.tracer.out.push(')');
+        Ok(value)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.tracer.out.push('{');
+        let value =
+            visitor.visit_seq(Elements::new(&mut *self.tracer, fields.len(), Some(fields)))?;
+        self.tracer.out.push('}');
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::fingerprint;
+
+    mod v1 {
+        #[derive(serde::Deserialize)]
+        pub struct Point {
+            pub x: i32,
+            pub y: i32,
+        }
+
+        #[derive(serde::Deserialize)]
+        pub enum Shape {
+            Circle { radius: f32 },
+            Square(u32),
+        }
+    }
+
+    mod v2 {
+        #[derive(serde::Deserialize)]
+        pub struct Point {
+            pub x: i32,
+            pub z: i32,
+        }
+
+        #[derive(serde::Deserialize)]
+        pub enum Shape {
+            Square(u32),
+            Circle { radius: f32 },
+        }
+    }
+
+    mod elsewhere {
+        #[derive(serde::Deserialize)]
+        pub struct Point {
+            pub x: i32,
+            pub y: i32,
+        }
+    }
+
+    #[derive(Deserialize)]
+    enum List {
+        Nil,
+        Cons(u32, Box<List>),
+    }
+
+    #[derive(Deserialize)]
+    struct Tree {
+        children: Vec<Tree>,
+        parent: Option<Box<Tree>>,
+        label: String,
+    }
+
+    mod tree_v2 {
+        #[derive(serde::Deserialize)]
+        pub struct Tree {
+            pub children: Vec<Tree>,
+            pub parent: Option<Box<Tree>>,
+            pub label: u64,
+        }
+    }
+
+    #[test]
+    fn module_paths_dont_matter() {
+        assert_eq!(
+            fingerprint::<v1::Point>(),
+            fingerprint::<elsewhere::Point>()
+        );
+    }
+
+    #[test]
+    fn renamed_fields_differ() {
+        assert_ne!(fingerprint::<v1::Point>(), fingerprint::<v2::Point>());
+    }
+
+    #[test]
+    fn reordered_variants_differ() {
+        assert_ne!(fingerprint::<v1::Shape>(), fingerprint::<v2::Shape>());
+    }
+
+    #[test]
+    fn recursive_types_are_traced() {
+        assert_eq!(super::trace::<List>().matches('\n').count(), 2);
+        assert!(!super::trace::<Tree>().contains('!'));
+        assert_ne!(fingerprint::<Tree>(), fingerprint::<tree_v2::Tree>());
+    }
+
+    #[test]
+    fn untraceable_types_are_fingerprinted_by_shape() {
+        #[derive(Deserialize)]
+        struct Dynamic {
+            _value: serde_json::Value,
+        }
+
+        mod other {
+            #[derive(serde::Deserialize)]
+            pub struct Dynamic {
+                pub _value: serde_json::Value,
+            }
+        }
+
+        assert!(super::trace::<Dynamic>().ends_with("!self-describing types can't be traced"));
+        assert_eq!(fingerprint::<Dynamic>(), fingerprint::<other::Dynamic>());
+    }
+
+    #[test]
+    fn fingerprints_are_cached() {
+        assert_eq!(fingerprint::<List>(), fingerprint::<List>());
+        assert_eq!(
+            fingerprint::<List>(),
+            super::fnv1a(super::trace::<List>().as_bytes())
+        );
+    }
+}
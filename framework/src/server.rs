@@ -0,0 +1,431 @@
+//! An accept loop for servers, which serves the root service on every session and shuts them
+//! all down gracefully
+
+use std::{fmt::Display, pin::pin, pin::Pin, sync::Arc, time::Duration};
+
+use futures::{
+    channel::oneshot,
+    future::{select, BoxFuture, Either, Shared},
+    stream::FusedStream,
+    Future, FutureExt, Stream, StreamExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tarpc::server::BaseChannel;
+use tarpc::{ClientMessage, Response, Transport};
+use tokio::task::JoinSet;
+
+use crate::auth::{Authenticator, Identity};
+use crate::handshake::FrameworkConfig;
+use crate::io::FrameworkError;
+use crate::peer_info::PeerInfo;
+use crate::session::AnySession;
+use crate::{MaybeSend, ServerFramework};
+
+/// Code the sessions are closed with when the server shuts down
+pub const SHUTDOWN_CODE: u32 = 0x53;
+
+/// The root transport of a session, boxed so that the factory given to `serve()` can name it
+pub type RootTransport<Req, Resp> =
+    Pin<Box<dyn Transport<Response<Resp>, ClientMessage<Req>, Error = FrameworkError> + Send>>;
+
+/// What `serve()` hands the factory along with the session's framework, to execute the root
+/// service on
+pub type RootChannel<Req, Resp> = BaseChannel<Req, Resp, RootTransport<Req, Resp>>;
+
+/// How `serve_with_shutdown()` runs
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    /// Connections beyond this wait to be accepted until others end
+    pub max_connections: Option<usize>,
+    /// How long the calls in flight get to finish once shutting down, before their sessions
+    /// are closed anyway
+    pub grace_period: Duration,
+    /// Config of every session, which the clients must agree with
+    pub config: FrameworkConfig,
+    /// Lets in only the clients whose credentials pass it, see
+    /// `ServerFramework::new_with_auth()`
+    pub authenticator: Option<SharedAuthenticator>,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            grace_period: Duration::from_secs(10),
+            config: FrameworkConfig::default(),
+            authenticator: None,
+        }
+    }
+}
+
+/// An `Authenticator` shared by all the sessions of a server, for `ServeOptions`
+#[derive(Clone)]
+pub struct SharedAuthenticator(Arc<dyn DynAuthenticator>);
+
+impl SharedAuthenticator {
+    pub fn new(authenticator: impl Authenticator + Sync) -> Self {
+        Self(Arc::new(authenticator))
+    }
+}
+
+impl std::fmt::Debug for SharedAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedAuthenticator")
+            .finish_non_exhaustive()
+    }
+}
+
+impl Authenticator for SharedAuthenticator {
+    fn authenticate(
+        &self,
+        credentials: Option<&str>,
+    ) -> impl Future<Output = Result<Identity, String>> + MaybeSend {
+        self.0.authenticate_boxed(credentials)
+    }
+}
+
+/// `Authenticator` with its futures boxed, so that it can be shared as a trait object
+trait DynAuthenticator: Send + Sync {
+    fn authenticate_boxed<'a>(
+        &'a self,
+        credentials: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Identity, String>>;
+}
+
+impl<A: Authenticator + Sync> DynAuthenticator for A {
+    fn authenticate_boxed<'a>(
+        &'a self,
+        credentials: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Identity, String>> {
+        Box::pin(self.authenticate(credentials))
+    }
+}
+
+/// Serves the root service executed by `factory` on every session from `incoming`, e.g.
+/// `webtransport::incoming()` or `websocket::incoming()`, until SIGTERM or Ctrl+C. See
+/// `serve_with_shutdown()`.
+pub async fn serve<L, C, Sess, E, Req, Resp, F, Calls, Call>(incoming: L, factory: F)
+where
+    L: Stream<Item = C>,
+    C: Future<Output = Result<(Sess, PeerInfo), E>> + Send + 'static,
+    Sess: Into<AnySession> + Send + 'static,
+    E: Display + Send + 'static,
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+    F: Fn(ServerFramework, RootChannel<Req, Resp>) -> Calls + Send + Sync + 'static,
+    Calls: Stream<Item = Call> + Send + 'static,
+    Call: Future<Output = ()> + Send + 'static,
+{
+    serve_with_shutdown(incoming, ServeOptions::default(), factory, terminated()).await
+}
+
+/// Serves the root service on every session from `incoming`, each with its own
+/// `ServerFramework` carrying the client's `PeerInfo`. Sessions which fail the handshake are
+/// dropped.
+///
+/// `factory` executes the service on the session's root channel, e.g.
+/// `|framework, channel| channel.execute(MyService::serve(MyServer { framework }))`. Each
+/// session is served on a task of its own, and each call on another, so the calls must be
+/// `Send`.
+///
+/// Once `shutdown` completes, stops accepting and drops the calls which come in after that,
+/// waits up to the grace period for the calls in flight, then closes the sessions with
+/// `SHUTDOWN_CODE`. Returns once they are all closed, or once `incoming` ends and the last
+/// session is over.
+pub async fn serve_with_shutdown<L, C, Sess, E, Req, Resp, F, Calls, Call>(
+    incoming: L,
+    options: ServeOptions,
+    factory: F,
+    shutdown: impl Future<Output = ()>,
+) where
+    L: Stream<Item = C>,
+    C: Future<Output = Result<(Sess, PeerInfo), E>> + Send + 'static,
+    Sess: Into<AnySession> + Send + 'static,
+    E: Display + Send + 'static,
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+    F: Fn(ServerFramework, RootChannel<Req, Resp>) -> Calls + Send + Sync + 'static,
+    Calls: Stream<Item = Call> + Send + 'static,
+    Call: Future<Output = ()> + Send + 'static,
+{
+    let (stop, stopping) = oneshot::channel();
+    let stopping = stopping.shared();
+
+    let options = Arc::new(options);
+    let factory = Arc::new(factory);
+    let mut incoming = pin!(incoming.fuse());
+    let mut shutdown = pin!(shutdown);
+    let mut connections = JoinSet::new();
+
+    loop {
+        if incoming.is_terminated() && connections.is_empty() {
+            return;
+        }
+        let full = options
+            .max_connections
+            .is_some_and(|max| connections.len() >= max);
+
+        tokio::select! {
+            _ = &mut shutdown => break,
+            Some(connecting) = incoming.next(), if !full && !incoming.is_terminated() => {
+                connections.spawn(connection(
+                    connecting,
+                    factory.clone(),
+                    options.clone(),
+                    stopping.clone(),
+                ));
+            }
+            _ = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    let _ = stop.send(());
+    while connections.join_next().await.is_some() {}
+}
+
+/// Completes on SIGTERM or Ctrl+C
+pub async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = sigterm.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Serves one session until it ends, or until the server shuts down
+async fn connection<C, Sess, E, Req, Resp, F, Calls, Call>(
+    connecting: C,
+    factory: Arc<F>,
+    options: Arc<ServeOptions>,
+    stopping: Shared<oneshot::Receiver<()>>,
+) where
+    C: Future<Output = Result<(Sess, PeerInfo), E>>,
+    Sess: Into<AnySession> + Send + 'static,
+    E: Display + Send + 'static,
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+    F: Fn(ServerFramework, RootChannel<Req, Resp>) -> Calls,
+    Calls: Stream<Item = Call>,
+    Call: Future<Output = ()> + Send + 'static,
+{
+    let setup = async {
+        let (sess, peer_info) = connecting.await.map_err(|e| e.to_string())?;
+        let config = options.config.clone();
+        let (framework, transport): (_, RootTransport<Req, Resp>) = match &options.authenticator {
+            Some(authenticator) => {
+                let (framework, transport) = ServerFramework::new_with_auth::<
+                    ClientMessage<Req>,
+                    Response<Resp>,
+                >(sess, config, authenticator.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+                (framework, Box::pin(transport))
+            }
+            None => {
+                let (framework, transport) = ServerFramework::new_with_config::<
+                    ClientMessage<Req>,
+                    Response<Resp>,
+                >(sess, config)
+                .await
+                .map_err(|e| e.to_string())?;
+                (framework, Box::pin(transport))
+            }
+        };
+        Ok::<_, String>((framework.with_peer_info(peer_info), transport))
+    };
+
+    let (framework, transport) = match select(pin!(setup), stopping.clone()).await {
+        Either::Left((Ok(setup), _)) => setup,
+        Either::Left((Err(e), _)) => {
+            log::warn!("Failed to accept a session: {e}");
+            return;
+        }
+        Either::Right(_) => return,
+    };
+
+    let channel = BaseChannel::with_defaults(transport);
+    let mut calls = pin!((*factory)(framework.clone(), channel));
+    let mut running = JoinSet::new();
+    let grace_period = options.grace_period;
+    let mut deadline = pin!(stopping.clone().then(move |_| crate::sleep(grace_period)));
+
+    loop {
+        let stopped = stopping.peek().is_some();
+        if stopped && running.is_empty() {
+            // Writes the responses of the calls which were done last
+            let _ = calls.next().now_or_never();
+            break;
+        }
+
+        // The responses are written while polling for the next call, so this keeps polling
+        // until the last call in flight is done
+        tokio::select! {
+            call = calls.next() => match call {
+                Some(call) if !stopped => {
+                    running.spawn(call);
+                }
+                // Dropping the call cancels it
+                Some(_) => {}
+                // The client has gone, so there is nobody left to answer or close
+                None => {
+                    running.detach_all();
+                    return;
+                }
+            },
+            _ = running.join_next(), if !running.is_empty() => {}
+            _ = stopping.clone(), if !stopped => {}
+            _ = &mut deadline => break,
+        }
+    }
+
+    framework.close(SHUTDOWN_CODE, "server shutting down").await;
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::future::{ready, Ready};
+    use tarpc::{context, server::Channel};
+    use tokio::{sync::Notify, task::JoinHandle, time::timeout};
+
+    use super::*;
+    use crate::session::MemorySession;
+    use crate::ClientFramework;
+
+    #[tarpc::service]
+    trait Napper {
+        /// Sleeps for `ms` milliseconds, then returns them
+        async fn nap(ms: u64) -> u64;
+    }
+
+    /// Notifies as each call starts
+    #[derive(Clone)]
+    struct NapperServer(Arc<Notify>);
+
+    impl Napper for NapperServer {
+        async fn nap(self, _: context::Context, ms: u64) -> u64 {
+            self.0.notify_one();
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            ms
+        }
+    }
+
+    type Connecting = Ready<Result<(MemorySession, PeerInfo), String>>;
+
+    /// A server of napping on a task, which takes its sessions from `incoming`
+    struct Server {
+        incoming: mpsc::UnboundedSender<Connecting>,
+        shutdown: oneshot::Sender<()>,
+        started: Arc<Notify>,
+        done: JoinHandle<()>,
+    }
+
+    impl Server {
+        fn start(options: ServeOptions) -> Self {
+            let (incoming, sessions) = mpsc::unbounded();
+            let (shutdown, stopping) = oneshot::channel();
+            let started = Arc::new(Notify::new());
+            let napper = NapperServer(started.clone());
+            let done = tokio::spawn(serve_with_shutdown(
+                sessions,
+                options,
+                move |_, channel: RootChannel<NapperRequest, NapperResponse>| {
+                    channel.execute(napper.clone().serve())
+                },
+                stopping.map(|_| ()),
+            ));
+            Self {
+                incoming,
+                shutdown,
+                started,
+                done,
+            }
+        }
+
+        /// Connects a client, which only completes once the server accepts the session
+        async fn connect(&self) -> (ClientFramework, NapperClient) {
+            let (client_sess, server_sess) = MemorySession::pair();
+            self.incoming
+                .unbounded_send(ready(Ok((server_sess, PeerInfo::new()))))
+                .unwrap();
+            let (client, transport) = ClientFramework::new::<
+                Response<NapperResponse>,
+                ClientMessage<NapperRequest>,
+            >(client_sess)
+            .await
+            .unwrap();
+            let napper = NapperClient::new(Default::default(), transport);
+            tokio::spawn(napper.dispatch);
+            (client, napper.client)
+        }
+    }
+
+    fn shut_down_with_code(error: FrameworkError) -> bool {
+        matches!(error, FrameworkError::Closed { code, .. } if code == SHUTDOWN_CODE)
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_limit_wait_for_one_to_end() {
+        let server = Server::start(ServeOptions {
+            max_connections: Some(1),
+            ..Default::default()
+        });
+        let (first, napper) = server.connect().await;
+        assert_eq!(napper.nap(context::current(), 0).await.unwrap(), 0);
+
+        let mut second = pin!(server.connect());
+        assert!(timeout(Duration::from_millis(100), &mut second)
+            .await
+            .is_err());
+
+        first.close(0, "done").await;
+        let (_second, napper) = timeout(Duration::from_secs(5), second).await.unwrap();
+        assert_eq!(napper.nap(context::current(), 0).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn shutting_down_lets_the_calls_in_flight_finish() {
+        let server = Server::start(ServeOptions::default());
+        let (client, napper) = server.connect().await;
+
+        let call = tokio::spawn(async move { napper.nap(context::current(), 100).await });
+        server.started.notified().await;
+        server.shutdown.send(()).unwrap();
+
+        assert_eq!(call.await.unwrap().unwrap(), 100);
+        assert!(shut_down_with_code(client.closed().await));
+        timeout(Duration::from_secs(5), server.done)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_past_the_grace_period_are_cut_short() {
+        let server = Server::start(ServeOptions {
+            grace_period: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let (client, napper) = server.connect().await;
+
+        let call = tokio::spawn(async move { napper.nap(context::current(), 60_000).await });
+        server.started.notified().await;
+        server.shutdown.send(()).unwrap();
+
+        assert!(shut_down_with_code(client.closed().await));
+        assert!(call.await.unwrap().is_err());
+        timeout(Duration::from_secs(5), server.done)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
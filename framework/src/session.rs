@@ -0,0 +1,667 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    task::{Context, Poll, Waker},
+};
+
+use bytes::Bytes;
+use futures::future::poll_fn;
+
+use crate::io::FrameworkError;
+#[cfg(feature = "websocket")]
+use crate::websocket::{WebSocketRecvStream, WebSocketSendStream, WebSocketSession};
+
+/// Number of datagrams waiting to be accepted on an emulated session before new ones are dropped
+const DATAGRAM_QUEUE_LEN: usize = 1024;
+
+/// A connection which carries streams and datagrams between two peers. Implemented for
+/// WebTransport sessions, by `MemorySession` for tests, and by `WebSocketSession` with the
+/// `websocket` feature.
+pub trait Session: Clone + 'static {
+    type SendStream: SendStream;
+    type RecvStream: RecvStream;
+
+    fn open_bi(
+        &self,
+    ) -> impl Future<Output = Result<(Self::SendStream, Self::RecvStream), FrameworkError>>;
+    fn accept_bi(
+        &self,
+    ) -> impl Future<Output = Result<(Self::SendStream, Self::RecvStream), FrameworkError>>;
+    fn open_uni(&self) -> impl Future<Output = Result<Self::SendStream, FrameworkError>>;
+    fn accept_uni(&self) -> impl Future<Output = Result<Self::RecvStream, FrameworkError>>;
+    fn send_datagram(&self, datagram: Bytes) -> impl Future<Output = Result<(), FrameworkError>>;
+    fn recv_datagram(&self) -> impl Future<Output = Result<Bytes, FrameworkError>>;
+    /// Closes the session right away, telling the peer the code and reason
+    fn close(&self, code: u32, reason: &str);
+}
+
+/// Sending half of a stream. The peer sees the end of the stream once this is dropped.
+pub trait SendStream: 'static {
+    /// Writes some of the buffer, returning how much
+    fn write(&mut self, buf: &[u8]) -> impl Future<Output = Result<usize, FrameworkError>>;
+}
+
+/// Receiving half of a stream
+pub trait RecvStream: 'static {
+    /// Reads at most `max` bytes. Returns `None` at the end of the stream.
+    fn read(&mut self, max: usize) -> impl Future<Output = Result<Option<Bytes>, FrameworkError>>;
+    /// Tells the peer to stop sending, which fails its writes with the given code
+    fn stop(&mut self, code: u32);
+}
+
+impl Session for web_transport::Session {
+    type SendStream = web_transport::SendStream;
+    type RecvStream = web_transport::RecvStream;
+
+    async fn open_bi(
+        &self,
+    ) -> Result<(web_transport::SendStream, web_transport::RecvStream), FrameworkError> {
+        Ok(web_transport::Session::open_bi(&mut self.clone()).await?)
+    }
+
+    async fn accept_bi(
+        &self,
+    ) -> Result<(web_transport::SendStream, web_transport::RecvStream), FrameworkError> {
+        Ok(web_transport::Session::accept_bi(&mut self.clone()).await?)
+    }
+
+    async fn open_uni(&self) -> Result<web_transport::SendStream, FrameworkError> {
+        Ok(web_transport::Session::open_uni(&mut self.clone()).await?)
+    }
+
+    async fn accept_uni(&self) -> Result<web_transport::RecvStream, FrameworkError> {
+        Ok(web_transport::Session::accept_uni(&mut self.clone()).await?)
+    }
+
+    async fn send_datagram(&self, datagram: Bytes) -> Result<(), FrameworkError> {
+        Ok(web_transport::Session::send_datagram(&mut self.clone(), datagram).await?)
+    }
+
+    async fn recv_datagram(&self) -> Result<Bytes, FrameworkError> {
+        Ok(web_transport::Session::recv_datagram(&mut self.clone()).await?)
+    }
+
+    fn close(&self, code: u32, reason: &str) {
+        web_transport::Session::close(self.clone(), code, reason);
+    }
+}
+
+impl SendStream for web_transport::SendStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, FrameworkError> {
+        Ok(web_transport::SendStream::write(self, buf).await?)
+    }
+}
+
+impl RecvStream for web_transport::RecvStream {
+    async fn read(&mut self, max: usize) -> Result<Option<Bytes>, FrameworkError> {
+        Ok(web_transport::RecvStream::read(self, max).await?)
+    }
+
+    fn stop(&mut self, code: u32) {
+        web_transport::RecvStream::stop(self, code);
+    }
+}
+
+/// Any of the sessions a framework can run on
+#[derive(Clone)]
+pub enum AnySession {
+    WebTransport(web_transport::Session),
+    Memory(MemorySession),
+    #[cfg(feature = "websocket")]
+    WebSocket(WebSocketSession),
+}
+
+/// Sending half of a stream on an `AnySession`
+pub enum AnySendStream {
+    WebTransport(web_transport::SendStream),
+    Memory(MemorySendStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(WebSocketSendStream),
+}
+
+/// Receiving half of a stream on an `AnySession`
+pub enum AnyRecvStream {
+    WebTransport(web_transport::RecvStream),
+    Memory(MemoryRecvStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(WebSocketRecvStream),
+}
+
+impl From<web_transport::Session> for AnySession {
+    fn from(value: web_transport::Session) -> Self {
+        Self::WebTransport(value)
+    }
+}
+
+impl From<MemorySession> for AnySession {
+    fn from(value: MemorySession) -> Self {
+        Self::Memory(value)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<WebSocketSession> for AnySession {
+    fn from(value: WebSocketSession) -> Self {
+        Self::WebSocket(value)
+    }
+}
+
+impl From<web_transport::SendStream> for AnySendStream {
+    fn from(value: web_transport::SendStream) -> Self {
+        Self::WebTransport(value)
+    }
+}
+
+impl From<MemorySendStream> for AnySendStream {
+    fn from(value: MemorySendStream) -> Self {
+        Self::Memory(value)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<WebSocketSendStream> for AnySendStream {
+    fn from(value: WebSocketSendStream) -> Self {
+        Self::WebSocket(value)
+    }
+}
+
+impl From<web_transport::RecvStream> for AnyRecvStream {
+    fn from(value: web_transport::RecvStream) -> Self {
+        Self::WebTransport(value)
+    }
+}
+
+impl From<MemoryRecvStream> for AnyRecvStream {
+    fn from(value: MemoryRecvStream) -> Self {
+        Self::Memory(value)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<WebSocketRecvStream> for AnyRecvStream {
+    fn from(value: WebSocketRecvStream) -> Self {
+        Self::WebSocket(value)
+    }
+}
+
+impl Session for AnySession {
+    type SendStream = AnySendStream;
+    type RecvStream = AnyRecvStream;
+
+    async fn open_bi(&self) -> Result<(AnySendStream, AnyRecvStream), FrameworkError> {
+        Ok(match self {
+            Self::WebTransport(sess) => {
+                let (tx, rx) = sess.open_bi().await?;
+                (tx.into(), rx.into())
+            }
+            Self::Memory(sess) => {
+                let (tx, rx) = sess.open_bi().await?;
+                (tx.into(), rx.into())
+            }
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(sess) => {
+                let (tx, rx) = sess.open_bi().await?;
+                (tx.into(), rx.into())
+            }
+        })
+    }
+
+    async fn accept_bi(&self) -> Result<(AnySendStream, AnyRecvStream), FrameworkError> {
+        Ok(match self {
+            Self::WebTransport(sess) => {
+                let (tx, rx) = sess.accept_bi().await?;
+                (tx.into(), rx.into())
+            }
+            Self::Memory(sess) => {
+                let (tx, rx) = sess.accept_bi().await?;
+                (tx.into(), rx.into())
+            }
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(sess) => {
+                let (tx, rx) = sess.accept_bi().await?;
+                (tx.into(), rx.into())
+            }
+        })
+    }
+
+    async fn open_uni(&self) -> Result<AnySendStream, FrameworkError> {
+        Ok(match self {
+            Self::WebTransport(sess) => sess.open_uni().await?.into(),
+            Self::Memory(sess) => sess.open_uni().await?.into(),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(sess) => sess.open_uni().await?.into(),
+        })
+    }
+
+    async fn accept_uni(&self) -> Result<AnyRecvStream, FrameworkError> {
+        Ok(match self {
+            Self::WebTransport(sess) => sess.accept_uni().await?.into(),
+            Self::Memory(sess) => sess.accept_uni().await?.into(),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(sess) => sess.accept_uni().await?.into(),
+        })
+    }
+
+    async fn send_datagram(&self, datagram: Bytes) -> Result<(), FrameworkError> {
+        match self {
+            Self::WebTransport(sess) => sess.send_datagram(datagram).await,
+            Self::Memory(sess) => sess.send_datagram(datagram).await,
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(sess) => sess.send_datagram(datagram).await,
+        }
+    }
+
+    async fn recv_datagram(&self) -> Result<Bytes, FrameworkError> {
+        match self {
+            Self::WebTransport(sess) => sess.recv_datagram().await,
+            Self::Memory(sess) => sess.recv_datagram().await,
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(sess) => sess.recv_datagram().await,
+        }
+    }
+
+    fn close(&self, code: u32, reason: &str) {
+        match self {
+            Self::WebTransport(sess) => Session::close(sess, code, reason),
+            Self::Memory(sess) => sess.close(code, reason),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(sess) => sess.close(code, reason),
+        }
+    }
+}
+
+impl SendStream for AnySendStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, FrameworkError> {
+        match self {
+            Self::WebTransport(tx) => SendStream::write(tx, buf).await,
+            Self::Memory(tx) => tx.write(buf).await,
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(tx) => tx.write(buf).await,
+        }
+    }
+}
+
+impl RecvStream for AnyRecvStream {
+    async fn read(&mut self, max: usize) -> Result<Option<Bytes>, FrameworkError> {
+        match self {
+            Self::WebTransport(rx) => RecvStream::read(rx, max).await,
+            Self::Memory(rx) => rx.read(max).await,
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(rx) => rx.read(max).await,
+        }
+    }
+
+    fn stop(&mut self, code: u32) {
+        match self {
+            Self::WebTransport(rx) => RecvStream::stop(rx, code),
+            Self::Memory(rx) => rx.stop(code),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(rx) => rx.stop(code),
+        }
+    }
+}
+
+/// One end of a session which lives entirely in memory, for testing services without any
+/// networking. Create the two ends with `MemorySession::pair()`, then create a
+/// `ClientFramework` on one and a `ServerFramework` on the other at the same time, e.g. with
+/// `futures::join!`, since each waits for the other's handshake.
+#[derive(Clone)]
+pub struct MemorySession {
+    state: Arc<Mutex<MemoryState>>,
+    /// Which of the two ends this is, as an index into `MemoryState::inboxes`
+    side: usize,
+}
+
+struct MemoryState {
+    /// What each end has yet to accept
+    inboxes: [Inbox<MemorySendStream, MemoryRecvStream>; 2],
+    /// Every stream opened so far, to be failed when the session is closed
+    pipes: Vec<Weak<Pipe>>,
+    ended: Option<SessionEnd>,
+}
+
+/// Streams and datagrams which one end of a session has yet to accept
+pub(crate) struct Inbox<Tx, Rx> {
+    pub bi: Queue<(Tx, Rx)>,
+    pub uni: Queue<Rx>,
+    pub datagrams: Queue<Bytes>,
+}
+
+impl<Tx, Rx> Default for Inbox<Tx, Rx> {
+    fn default() -> Self {
+        Self {
+            bi: Queue::default(),
+            uni: Queue::default(),
+            datagrams: Queue::default(),
+        }
+    }
+}
+
+impl<Tx, Rx> Inbox<Tx, Rx> {
+    /// Drops everything not yet accepted, and wakes up everyone waiting
+    pub fn clear(&mut self) {
+        self.bi.items.clear();
+        self.uni.items.clear();
+        self.datagrams.items.clear();
+        self.bi.wake();
+        self.uni.wake();
+        self.datagrams.wake();
+    }
+
+    /// Queues a datagram, unless too many are already waiting. Like a real network, datagrams
+    /// nobody is keeping up with are dropped.
+    pub fn push_datagram(&mut self, datagram: Bytes) {
+        if self.datagrams.items.len() < DATAGRAM_QUEUE_LEN {
+            self.datagrams.push(datagram);
+        }
+    }
+}
+
+pub(crate) struct Queue<T> {
+    items: VecDeque<T>,
+    waiting: Vec<Waker>,
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Self {
+            items: VecDeque::new(),
+            waiting: vec![],
+        }
+    }
+}
+
+impl<T> Queue<T> {
+    pub fn push(&mut self, item: T) {
+        self.items.push_back(item);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub fn poll_pop(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+        match self.items.pop_front() {
+            Some(item) => Poll::Ready(item),
+            None => {
+                if !self.waiting.iter().any(|w| w.will_wake(cx.waker())) {
+                    self.waiting.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Why a session ended
+#[derive(Clone, Debug)]
+pub(crate) enum SessionEnd {
+    /// Either side called `close()`
+    Closed { code: u32, reason: String },
+    /// The connection underneath was lost
+    Failed(String),
+}
+
+impl SessionEnd {
+    pub fn error(&self) -> FrameworkError {
+        match self {
+            Self::Closed { code, reason } => FrameworkError::Closed {
+                code: *code,
+                reason: reason.clone(),
+            },
+            Self::Failed(msg) => FrameworkError::Transport(msg.clone()),
+        }
+    }
+}
+
+impl MemorySession {
+    /// Creates both ends of a new session
+    pub fn pair() -> (Self, Self) {
+        let state = Arc::new(Mutex::new(MemoryState {
+            inboxes: Default::default(),
+            pipes: vec![],
+            ended: None,
+        }));
+        let first = Self {
+            state: state.clone(),
+            side: 0,
+        };
+        let second = Self { state, side: 1 };
+        (first, second)
+    }
+
+    /// Creates one direction of a stream
+    fn pipe(state: &mut MemoryState) -> (MemorySendStream, MemoryRecvStream) {
+        let pipe = Arc::new(Pipe::default());
+        state.pipes.retain(|pipe| pipe.strong_count() > 0);
+        state.pipes.push(Arc::downgrade(&pipe));
+        (
+            MemorySendStream { pipe: pipe.clone() },
+            MemoryRecvStream { pipe },
+        )
+    }
+
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    /// Locks the state, failing if the session has ended
+    fn open(&self) -> Result<MutexGuard<'_, MemoryState>, FrameworkError> {
+        let state = self.state.lock().unwrap();
+        match &state.ended {
+            Some(end) => Err(end.error()),
+            None => Ok(state),
+        }
+    }
+
+    async fn accept<T>(
+        &self,
+        queue: impl Fn(&mut Inbox<MemorySendStream, MemoryRecvStream>) -> &mut Queue<T>,
+    ) -> Result<T, FrameworkError> {
+        poll_fn(|cx| {
+            let mut state = self.open()?;
+            queue(&mut state.inboxes[self.side]).poll_pop(cx).map(Ok)
+        })
+        .await
+    }
+}
+
+impl Session for MemorySession {
+    type SendStream = MemorySendStream;
+    type RecvStream = MemoryRecvStream;
+
+    async fn open_bi(&self) -> Result<(MemorySendStream, MemoryRecvStream), FrameworkError> {
+        let mut state = self.open()?;
+        let (our_tx, their_rx) = Self::pipe(&mut state);
+        let (their_tx, our_rx) = Self::pipe(&mut state);
+        state.inboxes[self.peer()].bi.push((their_tx, their_rx));
+        Ok((our_tx, our_rx))
+    }
+
+    async fn accept_bi(&self) -> Result<(MemorySendStream, MemoryRecvStream), FrameworkError> {
+        self.accept(|inbox| &mut inbox.bi).await
+    }
+
+    async fn open_uni(&self) -> Result<MemorySendStream, FrameworkError> {
+        let mut state = self.open()?;
+        let (tx, rx) = Self::pipe(&mut state);
+        state.inboxes[self.peer()].uni.push(rx);
+        Ok(tx)
+    }
+
+    async fn accept_uni(&self) -> Result<MemoryRecvStream, FrameworkError> {
+        self.accept(|inbox| &mut inbox.uni).await
+    }
+
+    async fn send_datagram(&self, datagram: Bytes) -> Result<(), FrameworkError> {
+        let mut state = self.open()?;
+        state.inboxes[self.peer()].push_datagram(datagram);
+        Ok(())
+    }
+
+    async fn recv_datagram(&self) -> Result<Bytes, FrameworkError> {
+        self.accept(|inbox| &mut inbox.datagrams).await
+    }
+
+    fn close(&self, code: u32, reason: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.ended.is_some() {
+            return;
+        }
+        let end = SessionEnd::Closed {
+            code,
+            reason: reason.to_string(),
+        };
+
+        for pipe in state.pipes.drain(..).filter_map(|pipe| pipe.upgrade()) {
+            pipe.end(&end);
+        }
+        for inbox in &mut state.inboxes {
+            inbox.clear();
+        }
+        state.ended = Some(end);
+    }
+}
+
+/// One direction of a stream, buffered in memory until it is read
+#[derive(Default)]
+pub(crate) struct Pipe(Mutex<PipeState>);
+
+#[derive(Default)]
+struct PipeState {
+    chunks: VecDeque<Bytes>,
+    /// The sending end was dropped
+    finished: bool,
+    /// Code the receiving end stopped the stream with
+    stopped: Option<u32>,
+    ended: Option<SessionEnd>,
+    reader: Option<Waker>,
+}
+
+impl PipeState {
+    fn wake(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+impl Pipe {
+    /// Fails if the stream can't be written to anymore
+    pub fn check_writable(&self) -> Result<(), FrameworkError> {
+        let pipe = self.0.lock().unwrap();
+        if let Some(end) = &pipe.ended {
+            return Err(end.error());
+        }
+        if let Some(code) = pipe.stopped {
+            return Err(FrameworkError::StreamReset { code });
+        }
+        Ok(())
+    }
+
+    /// Adds data for the reader. Dropped if the reader has stopped the stream.
+    pub fn push(&self, bytes: Bytes) {
+        let mut pipe = self.0.lock().unwrap();
+        if pipe.stopped.is_none() {
+            pipe.chunks.push_back(bytes);
+            pipe.wake();
+        }
+    }
+
+    /// Ends the stream for the reader, once it has read everything before
+    pub fn finish(&self) {
+        let mut pipe = self.0.lock().unwrap();
+        pipe.finished = true;
+        pipe.wake();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.lock().unwrap().finished
+    }
+
+    /// Stops the stream, failing later writes with the given code
+    pub fn stop(&self, code: u32) {
+        let mut pipe = self.0.lock().unwrap();
+        pipe.stopped.get_or_insert(code);
+        pipe.chunks.clear();
+    }
+
+    /// Fails reads and writes once the session has ended
+    pub fn end(&self, end: &SessionEnd) {
+        let mut pipe = self.0.lock().unwrap();
+        pipe.ended.get_or_insert_with(|| end.clone());
+        pipe.wake();
+    }
+
+    pub fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        max: usize,
+    ) -> Poll<Result<Option<Bytes>, FrameworkError>> {
+        let mut pipe = self.0.lock().unwrap();
+        if let Some(end) = &pipe.ended {
+            return Poll::Ready(Err(end.error()));
+        }
+        if let Some(chunk) = pipe.chunks.front_mut() {
+            let bytes = if chunk.len() > max {
+                chunk.split_to(max)
+            } else {
+                pipe.chunks.pop_front().unwrap()
+            };
+            return Poll::Ready(Ok(Some(bytes)));
+        }
+        if pipe.finished {
+            return Poll::Ready(Ok(None));
+        }
+        pipe.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Sending half of a stream on a `MemorySession`. Writes never wait, the data is buffered until
+/// the peer reads it.
+pub struct MemorySendStream {
+    pipe: Arc<Pipe>,
+}
+
+/// Receiving half of a stream on a `MemorySession`
+pub struct MemoryRecvStream {
+    pipe: Arc<Pipe>,
+}
+
+impl SendStream for MemorySendStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, FrameworkError> {
+        self.pipe.check_writable()?;
+        self.pipe.push(Bytes::copy_from_slice(buf));
+        Ok(buf.len())
+    }
+}
+
+impl Drop for MemorySendStream {
+    fn drop(&mut self) {
+        self.pipe.finish();
+    }
+}
+
+impl RecvStream for MemoryRecvStream {
+    async fn read(&mut self, max: usize) -> Result<Option<Bytes>, FrameworkError> {
+        poll_fn(|cx| self.pipe.poll_read(cx, max)).await
+    }
+
+    fn stop(&mut self, code: u32) {
+        self.pipe.stop(code);
+    }
+}
+
+impl Drop for MemoryRecvStream {
+    fn drop(&mut self) {
+        // Like QUIC, tell the sender nobody is listening anymore
+        if !self.pipe.is_finished() {
+            self.pipe.stop(0);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// What a stream was opened for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    Root,
+    BiStream,
+    Subservice,
+    OfferedService,
+    Downstream,
+    Upstream,
+    ResultStream,
+}
+
+impl StreamKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Root => "root",
+            Self::BiStream => "bistream",
+            Self::Subservice => "subservice",
+            Self::OfferedService => "offered_service",
+            Self::Downstream => "downstream",
+            Self::Upstream => "upstream",
+            Self::ResultStream => "result_stream",
+        }
+    }
+}
+
+/// Traffic of one stream, or of all streams of one kind and service. Bytes are counted as they
+/// go over the wire, after compression and including the frame headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Time spent encoding and compressing. Not measured in the browser.
+    pub encode_time: Duration,
+    /// Time spent decompressing and decoding. Not measured in the browser.
+    pub decode_time: Duration,
+    pub errors: u64,
+}
+
+impl TrafficStats {
+    fn add(&mut self, other: &TrafficStats) {
+        self.messages_sent += other.messages_sent;
+        self.messages_received += other.messages_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.encode_time += other.encode_time;
+        self.decode_time += other.decode_time;
+        self.errors += other.errors;
+    }
+
+    /// What was added since `earlier`
+    #[cfg(feature = "metrics")]
+    fn since(&self, earlier: &TrafficStats) -> TrafficStats {
+        TrafficStats {
+            messages_sent: self.messages_sent.saturating_sub(earlier.messages_sent),
+            messages_received: self
+                .messages_received
+                .saturating_sub(earlier.messages_received),
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            encode_time: self.encode_time.saturating_sub(earlier.encode_time),
+            decode_time: self.decode_time.saturating_sub(earlier.decode_time),
+            errors: self.errors.saturating_sub(earlier.errors),
+        }
+    }
+}
+
+/// A stream which is still open
+#[derive(Clone, Debug)]
+pub struct StreamStats {
+    /// Tells streams apart between snapshots
+    pub id: u64,
+    pub kind: StreamKind,
+    /// The root service's name, the client type of a subservice, or the message types of
+    /// other streams
+    pub service: String,
+    pub traffic: TrafficStats,
+}
+
+/// Traffic of the framed streams of a session, from `stats()`. Raw streams and datagrams
+/// aren't counted.
+#[derive(Clone, Debug, Default)]
+pub struct SessionStats {
+    /// Streams which are still open
+    pub streams: Vec<StreamStats>,
+    /// Totals of all streams, including closed ones, by kind and service
+    pub totals: HashMap<(StreamKind, String), TrafficStats>,
+}
+
+impl SessionStats {
+    /// Sum of all streams
+    pub fn total(&self) -> TrafficStats {
+        let mut total = TrafficStats::default();
+        for traffic in self.totals.values() {
+            total.add(traffic);
+        }
+        total
+    }
+
+    /// The totals in the Prometheus text format, labelled by kind and service. Use
+    /// `stats::to_prometheus()` to export several sessions together.
+    pub fn to_prometheus(&self) -> String {
+        write_prometheus(&[(None, self)])
+    }
+}
+
+/// The totals of several sessions in the Prometheus text format, e.g. of every client of a
+/// server. Each is labelled by the name given with it, such as the peer's address or identity,
+/// besides kind and service.
+pub fn to_prometheus<'a>(
+    sessions: impl IntoIterator<Item = (&'a str, &'a SessionStats)>,
+) -> String {
+    let sessions: Vec<_> = sessions
+        .into_iter()
+        .map(|(name, stats)| (Some(name), stats))
+        .collect();
+    write_prometheus(&sessions)
+}
+
+/// Writes every metric once, with a series for each session, kind and service
+fn write_prometheus(sessions: &[(Option<&str>, &SessionStats)]) -> String {
+    let metrics: [(&str, &str, fn(&TrafficStats) -> f64); 7] = [
+        ("messages_sent", "Messages sent", |t| t.messages_sent as f64),
+        ("messages_received", "Messages received", |t| {
+            t.messages_received as f64
+        }),
+        ("bytes_sent", "Bytes sent", |t| t.bytes_sent as f64),
+        ("bytes_received", "Bytes received", |t| {
+            t.bytes_received as f64
+        }),
+        ("encode_seconds", "Time spent encoding", |t| {
+            t.encode_time.as_secs_f64()
+        }),
+        ("decode_seconds", "Time spent decoding", |t| {
+            t.decode_time.as_secs_f64()
+        }),
+        ("errors", "Stream errors", |t| t.errors as f64),
+    ];
+
+    let mut series = vec![];
+    for (session, stats) in sessions {
+        let mut totals: Vec<_> = stats.totals.iter().collect();
+        totals.sort_by(|(a, _), (b, _)| (a.0.name(), &a.1).cmp(&(b.0.name(), &b.1)));
+        for ((kind, service), traffic) in totals {
+            let mut labels = String::new();
+            if let Some(session) = session {
+                let _ = write!(labels, "session=\"{}\",", escape(session));
+            }
+            let _ = write!(
+                labels,
+                "kind=\"{}\",service=\"{}\"",
+                kind.name(),
+                escape(service)
+            );
+            series.push((labels, traffic));
+        }
+    }
+
+    let mut out = String::new();
+    for (name, help, value) in metrics {
+        let _ = writeln!(out, "# HELP framework_{name}_total {help}");
+        let _ = writeln!(out, "# TYPE framework_{name}_total counter");
+        for (labels, traffic) in &series {
+            let _ = writeln!(out, "framework_{name}_total{{{labels}}} {}", value(traffic));
+        }
+    }
+    out
+}
+
+/// Escapes a Prometheus label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    encode_nanos: AtomicU64,
+    decode_nanos: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Counts the traffic of one stream. The default counter isn't part of any session's stats.
+#[derive(Clone, Default)]
+pub(crate) struct StreamCounter(Arc<Counters>);
+
+impl StreamCounter {
+    pub fn sent(&self, bytes: usize, encode_time: Duration) {
+        let counters = &self.0;
+        counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        counters
+            .encode_nanos
+            .fetch_add(encode_time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize, decode_time: Duration) {
+        let counters = &self.0;
+        counters.messages_received.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        counters
+            .decode_nanos
+            .fetch_add(decode_time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.0.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn traffic(&self) -> TrafficStats {
+        let counters = &self.0;
+        TrafficStats {
+            messages_sent: counters.messages_sent.load(Ordering::Relaxed),
+            messages_received: counters.messages_received.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+            encode_time: Duration::from_nanos(counters.encode_nanos.load(Ordering::Relaxed)),
+            decode_time: Duration::from_nanos(counters.decode_nanos.load(Ordering::Relaxed)),
+            errors: counters.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the stream's halves have all been dropped, leaving just the registry's copy
+    fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+}
+
+/// Keeps the counters of all framed streams of a session
+#[derive(Clone, Default)]
+pub(crate) struct StatsRegistry(Arc<Mutex<RegistryState>>);
+
+#[derive(Default)]
+struct RegistryState {
+    next_id: u64,
+    open: Vec<(StreamStats, StreamCounter)>,
+    /// Totals of the closed streams
+    closed: HashMap<(StreamKind, String), TrafficStats>,
+    /// Totals as of the last `record_metrics()`
+    #[cfg(feature = "metrics")]
+    recorded: HashMap<(StreamKind, String), TrafficStats>,
+}
+
+impl RegistryState {
+    /// Folds the streams which have closed into the totals
+    fn prune(&mut self) {
+        let closed = &mut self.closed;
+        self.open.retain(|(stats, counter)| {
+            if !counter.is_closed() {
+                return true;
+            }
+            // Nothing counts on it anymore
+            closed
+                .entry((stats.kind, stats.service.clone()))
+                .or_default()
+                .add(&counter.traffic());
+            false
+        });
+    }
+}
+
+impl StatsRegistry {
+    /// A counter for a new stream. Those of closed streams are folded into the totals here as
+    /// well, so that a session which is never asked for its stats doesn't keep them all.
+    pub fn counter(&self, kind: StreamKind, service: impl Into<String>) -> StreamCounter {
+        let mut state = self.0.lock().unwrap();
+        state.prune();
+        let counter = StreamCounter::default();
+        let stats = StreamStats {
+            id: state.next_id,
+            kind,
+            service: service.into(),
+            traffic: TrafficStats::default(),
+        };
+        state.next_id += 1;
+        state.open.push((stats, counter.clone()));
+        counter
+    }
+
+    /// The open streams, and the totals including those which have closed
+    pub fn snapshot(&self) -> SessionStats {
+        let mut state = self.0.lock().unwrap();
+        state.prune();
+
+        let streams: Vec<_> = state
+            .open
+            .iter()
+            .map(|(stats, counter)| StreamStats {
+                traffic: counter.traffic(),
+                ..stats.clone()
+            })
+            .collect();
+
+        let mut totals = state.closed.clone();
+        for stream in &streams {
+            totals
+                .entry((stream.kind, stream.service.clone()))
+                .or_default()
+                .add(&stream.traffic);
+        }
+
+        SessionStats { streams, totals }
+    }
+
+    /// Adds the traffic since the last call to the `metrics` crate's counters, labelled by
+    /// kind and service. The counters are shared by all sessions, so each only adds its own
+    /// traffic to them.
+    #[cfg(feature = "metrics")]
+    pub fn record_metrics(&self) {
+        let totals = self.snapshot().totals;
+        let mut state = self.0.lock().unwrap();
+        for ((kind, service), traffic) in totals {
+            let recorded = state.recorded.entry((kind, service.clone())).or_default();
+            let delta = traffic.since(recorded);
+            *recorded = traffic;
+
+            let labels = [("kind", kind.name().to_string()), ("service", service)];
+            let counters = [
+                ("framework_messages_sent_total", delta.messages_sent),
+                ("framework_messages_received_total", delta.messages_received),
+                ("framework_bytes_sent_total", delta.bytes_sent),
+                ("framework_bytes_received_total", delta.bytes_received),
+                (
+                    "framework_encode_micros_total",
+                    delta.encode_time.as_micros() as u64,
+                ),
+                (
+                    "framework_decode_micros_total",
+                    delta.decode_time.as_micros() as u64,
+                ),
+                ("framework_errors_total", delta.errors),
+            ];
+            for (name, value) in counters {
+                metrics::counter!(name, &labels[..]).increment(value);
+            }
+        }
+    }
+}
+
+/// Runs `f`, measuring how long it takes. Not measured in the browser, where `Instant` isn't
+/// available.
+pub(crate) fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let start = std::time::Instant::now();
+        let output = f();
+        (output, start.elapsed())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        (f(), Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn counters_add_up() {
+        let registry = StatsRegistry::default();
+        let counter = registry.counter(StreamKind::Downstream, "u32");
+        counter.sent(10, MS);
+        counter.sent(20, MS);
+        counter.received(5, MS);
+        counter.error();
+
+        let expected = TrafficStats {
+            messages_sent: 2,
+            messages_received: 1,
+            bytes_sent: 30,
+            bytes_received: 5,
+            encode_time: 2 * MS,
+            decode_time: MS,
+            errors: 1,
+        };
+        let stats = registry.snapshot();
+        assert_eq!(stats.streams.len(), 1);
+        assert_eq!(stats.streams[0].traffic, expected);
+        assert_eq!(stats.total(), expected);
+    }
+
+    #[test]
+    fn closed_streams_are_kept_only_in_the_totals() {
+        let registry = StatsRegistry::default();
+        for _ in 0..100 {
+            registry.counter(StreamKind::Upstream, "u32").sent(1, MS);
+        }
+        let open = registry.counter(StreamKind::Upstream, "u32");
+        open.sent(1, MS);
+        assert_eq!(registry.0.lock().unwrap().open.len(), 1);
+
+        let stats = registry.snapshot();
+        assert_eq!(stats.streams.len(), 1);
+        let key = (StreamKind::Upstream, "u32".to_string());
+        assert_eq!(stats.totals[&key].messages_sent, 101);
+    }
+
+    #[test]
+    fn prometheus_export_labels_each_session() {
+        let stats = |bytes| {
+            let registry = StatsRegistry::default();
+            registry.counter(StreamKind::Root, "Chat").sent(bytes, MS);
+            registry.snapshot()
+        };
+        let (alice, bob) = (stats(10), stats(20));
+
+        let out = to_prometheus([("alice", &alice), ("bob \"2\"", &bob)]);
+        assert_eq!(out.matches("# TYPE framework_bytes_sent_total").count(), 1);
+        assert!(out.contains(
+            "framework_bytes_sent_total{session=\"alice\",kind=\"root\",service=\"Chat\"} 10\n"
+        ));
+        assert!(out.contains(
+            "framework_bytes_sent_total{session=\"bob \\\"2\\\"\",kind=\"root\",service=\"Chat\"} 20\n"
+        ));
+
+        assert!(alice
+            .to_prometheus()
+            .contains("framework_bytes_sent_total{kind=\"root\",service=\"Chat\"} 10\n"));
+    }
+}
@@ -0,0 +1,982 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+use bytes::Bytes;
+use futures::{
+    future::{poll_fn, ready},
+    Sink, SinkExt, Stream, StreamExt, TryStreamExt,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite;
+
+use crate::io::{decode, encode, FrameworkError, STREAM_REFUSED_CODE};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::peer_info::PeerInfo;
+use crate::session::{Inbox, Pipe, Queue, RecvStream, SendStream, Session, SessionEnd};
+use crate::{MaybeSend, Role};
+
+/// Largest piece of a stream sent in one WebSocket message
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// Bytes of a stream sent ahead of what the receiver has read, which is all it buffers for it
+const STREAM_WINDOW: usize = 256 * 1024;
+/// Bytes of stream data and datagrams queued for the socket, beyond which writers wait
+const MAX_QUEUED: usize = 1024 * 1024;
+/// Streams the peer may have open at once. Those it opens beyond are refused, as QUIC's stream
+/// limit would.
+const MAX_REMOTE_STREAMS: usize = 1024;
+
+/// What each WebSocket message carries. Streams are numbered by the side which opens them, the
+/// client's even and the server's odd, like in QUIC.
+#[derive(Serialize, Deserialize)]
+enum Packet {
+    Open {
+        id: u64,
+        bi: bool,
+    },
+    Data {
+        id: u64,
+        data: Vec<u8>,
+    },
+    /// The sender is done with the stream
+    Finish {
+        id: u64,
+    },
+    /// The receiver doesn't want any more of the stream
+    Stop {
+        id: u64,
+        code: u32,
+    },
+    /// The receiver has read this much more of the stream, so the sender may send it again
+    Credit {
+        id: u64,
+        bytes: u64,
+    },
+    Datagram(Vec<u8>),
+    Close {
+        code: u32,
+        reason: String,
+    },
+}
+
+impl Packet {
+    /// Bytes counted against `MAX_QUEUED`. Control messages are small and always let in.
+    fn queued_len(&self) -> usize {
+        match self {
+            Packet::Data { data, .. } | Packet::Datagram(data) => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// A session which multiplexes streams and datagrams over a single WebSocket, for clients
+/// which can't use WebTransport. Frameworks and tokens work the same on it, though streams
+/// share the socket's ordering, and datagrams are delivered reliably. Like in QUIC, each
+/// stream may only send `STREAM_WINDOW` bytes ahead of what the peer has read.
+///
+/// Connect with `connect()`, and accept connections on the server with `accept()`.
+#[derive(Clone)]
+pub struct WebSocketSession {
+    state: Arc<Mutex<WebSocketState>>,
+    outbox: Arc<Outbox>,
+}
+
+struct WebSocketState {
+    next_id: u64,
+    /// Lowest id the peer may open a stream with next
+    next_remote_id: u64,
+    /// Receiving halves of the open streams, by id
+    readers: HashMap<u64, Reader>,
+    /// Sending halves of the open streams, to stop them or grant them credit
+    writers: HashMap<u64, Arc<Writer>>,
+    inbox: Inbox<WebSocketSendStream, WebSocketRecvStream>,
+    ended: Option<SessionEnd>,
+}
+
+/// Receiving half of a stream as the session sees it
+struct Reader {
+    pipe: Arc<Pipe>,
+    /// Bytes the peer may still send before it is granted more
+    window: usize,
+}
+
+impl WebSocketSession {
+    /// Runs a session over a socket which sends and receives binary messages, such as a
+    /// WebSocket. The server and the client must be on different sides.
+    /// Warning: spawns tasks underneath
+    pub fn new<S, E>(role: Role, socket: S) -> Self
+    where
+        S: Sink<Bytes, Error = E> + Stream<Item = Result<Bytes, E>> + MaybeSend + 'static,
+        E: Display + MaybeSend + 'static,
+    {
+        let sess = Self {
+            state: Arc::new(Mutex::new(WebSocketState {
+                next_id: match role {
+                    Role::Client => 0,
+                    Role::Server => 1,
+                },
+                next_remote_id: match role {
+                    Role::Client => 1,
+                    Role::Server => 0,
+                },
+                readers: HashMap::new(),
+                writers: HashMap::new(),
+                inbox: Inbox::default(),
+                ended: None,
+            })),
+            outbox: Arc::default(),
+        };
+
+        let (mut sink, mut stream) = socket.split();
+
+        let session = sess.clone();
+        crate::spawn(async move {
+            while let Some(packet) = poll_fn(|cx| session.outbox.poll_pop(cx)).await {
+                let sent = match encode(&packet) {
+                    Ok(msg) => sink.send(msg.into()).await.map_err(transport_error),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    // Otherwise writers would wait for room forever
+                    session.end(SessionEnd::Failed(e.to_string()));
+                    return;
+                }
+            }
+
+            // The session has ended, let the peer know we are gone
+            let _ = sink.close().await;
+        });
+
+        let session = sess.clone();
+        crate::spawn(async move {
+            let end = loop {
+                let packet = match stream.next().await {
+                    Some(Ok(msg)) => match decode::<Packet>(&msg) {
+                        Ok(packet) => packet,
+                        Err(e) => break SessionEnd::Failed(e.to_string()),
+                    },
+                    Some(Err(e)) => break SessionEnd::Failed(e.to_string()),
+                    None => break SessionEnd::Failed("WebSocket closed".into()),
+                };
+
+                if let Some(end) = session.receive(packet) {
+                    break end;
+                }
+            };
+
+            session.end(end);
+        });
+
+        sess
+    }
+
+    /// Handles a message from the peer. Returns why the session ended, if it did.
+    fn receive(&self, packet: Packet) -> Option<SessionEnd> {
+        let mut state = self.state.lock().unwrap();
+        if state.ended.is_some() {
+            return None;
+        }
+
+        match packet {
+            Packet::Open { id, bi } => {
+                // Ids only ever go up, so this also catches one which was used before
+                if id % 2 != state.next_remote_id % 2 || id < state.next_remote_id {
+                    let error = format!("WebSocket stream {id} can't be opened by the peer");
+                    return Some(SessionEnd::Failed(error));
+                }
+                state.next_remote_id = match id.checked_add(2) {
+                    Some(next) => next,
+                    None => return Some(SessionEnd::Failed("WebSocket ran out of ids".into())),
+                };
+
+                if Self::remote_streams(&state) >= MAX_REMOTE_STREAMS {
+                    let _ = self.send(Packet::Stop {
+                        id,
+                        code: STREAM_REFUSED_CODE,
+                    });
+                    if bi {
+                        let _ = self.send(Packet::Finish { id });
+                    }
+                    return None;
+                }
+
+                let rx = self.reader(&mut state, id);
+                if bi {
+                    let tx = self.writer(&mut state, id);
+                    state.inbox.bi.push((tx, rx));
+                } else {
+                    state.inbox.uni.push(rx);
+                }
+            }
+            Packet::Data { id, data } => {
+                // Data for a stream we stopped is dropped
+                if let Some(reader) = state.readers.get_mut(&id) {
+                    if data.len() > reader.window {
+                        let error = format!("WebSocket stream {id} sent past its window");
+                        return Some(SessionEnd::Failed(error));
+                    }
+                    reader.window -= data.len();
+                    reader.pipe.push(data.into());
+                }
+            }
+            Packet::Finish { id } => {
+                if let Some(reader) = state.readers.remove(&id) {
+                    reader.pipe.finish();
+                }
+            }
+            Packet::Stop { id, code } => {
+                if let Some(writer) = state.writers.remove(&id) {
+                    writer.stop(code);
+                }
+            }
+            Packet::Credit { id, bytes } => {
+                if let Some(writer) = state.writers.get(&id) {
+                    writer.grant(usize::try_from(bytes).unwrap_or(usize::MAX));
+                }
+            }
+            Packet::Datagram(datagram) => state.inbox.push_datagram(datagram.into()),
+            Packet::Close { code, reason } => return Some(SessionEnd::Closed { code, reason }),
+        }
+
+        None
+    }
+
+    /// Fails every stream, and stops writing to the socket
+    fn end(&self, end: SessionEnd) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if state.ended.is_some() {
+            return;
+        }
+
+        for (_, reader) in state.readers.drain() {
+            reader.pipe.end(&end);
+        }
+        for (_, writer) in state.writers.drain() {
+            writer.end(&end);
+        }
+        state.ended = Some(end);
+        self.outbox.close();
+
+        // Streams nobody accepted lock the state when they are dropped
+        let mut inbox = std::mem::take(&mut state.inbox);
+        drop(guard);
+        inbox.clear();
+    }
+
+    /// Locks the state, failing if the session has ended
+    fn open(&self) -> Result<MutexGuard<'_, WebSocketState>, FrameworkError> {
+        let state = self.state.lock().unwrap();
+        match &state.ended {
+            Some(end) => Err(end.error()),
+            None => Ok(state),
+        }
+    }
+
+    /// Queues a message for the socket, without waiting for room
+    fn send(&self, packet: Packet) -> Result<(), FrameworkError> {
+        self.outbox.push(packet)
+    }
+
+    /// Lets the peer send `bytes` more of a stream, once they have been read
+    fn grant(&self, id: u64, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        // Not if the stream has finished or was stopped
+        if let Some(reader) = state.readers.get_mut(&id) {
+            reader.window += bytes;
+            let _ = self.send(Packet::Credit {
+                id,
+                bytes: bytes as u64,
+            });
+        }
+    }
+
+    fn next_id(state: &mut WebSocketState) -> u64 {
+        let id = state.next_id;
+        state.next_id += 2;
+        id
+    }
+
+    /// Streams opened by the peer which either side still uses
+    fn remote_streams(state: &WebSocketState) -> usize {
+        let remote = |id: &&u64| **id % 2 == state.next_remote_id % 2;
+        let readers = state.readers.keys().filter(remote).count();
+        // Those of bi streams which are also reading are counted already
+        let writing = |id: &&u64| !state.readers.contains_key(*id);
+        let writers = state.writers.keys().filter(remote).filter(writing).count();
+        readers + writers
+    }
+
+    fn reader(&self, state: &mut WebSocketState, id: u64) -> WebSocketRecvStream {
+        let pipe = Arc::new(Pipe::default());
+        let reader = Reader {
+            pipe: pipe.clone(),
+            window: STREAM_WINDOW,
+        };
+        state.readers.insert(id, reader);
+        WebSocketRecvStream {
+            id,
+            pipe,
+            unacknowledged: 0,
+            session: self.clone(),
+        }
+    }
+
+    fn writer(&self, state: &mut WebSocketState, id: u64) -> WebSocketSendStream {
+        let writer = Arc::new(Writer::new());
+        state.writers.insert(id, writer.clone());
+        WebSocketSendStream {
+            id,
+            writer,
+            session: self.clone(),
+        }
+    }
+
+    async fn accept<T>(
+        &self,
+        queue: impl Fn(&mut Inbox<WebSocketSendStream, WebSocketRecvStream>) -> &mut Queue<T>,
+    ) -> Result<T, FrameworkError> {
+        poll_fn(|cx| {
+            let mut state = self.open()?;
+            queue(&mut state.inbox).poll_pop(cx).map(Ok)
+        })
+        .await
+    }
+}
+
+impl Session for WebSocketSession {
+    type SendStream = WebSocketSendStream;
+    type RecvStream = WebSocketRecvStream;
+
+    async fn open_bi(&self) -> Result<(WebSocketSendStream, WebSocketRecvStream), FrameworkError> {
+        let mut state = self.open()?;
+        let id = Self::next_id(&mut state);
+        // Before creating the streams, which lock the state when they are dropped
+        self.send(Packet::Open { id, bi: true })?;
+        let tx = self.writer(&mut state, id);
+        let rx = self.reader(&mut state, id);
+        Ok((tx, rx))
+    }
+
+    async fn accept_bi(
+        &self,
+    ) -> Result<(WebSocketSendStream, WebSocketRecvStream), FrameworkError> {
+        self.accept(|inbox| &mut inbox.bi).await
+    }
+
+    async fn open_uni(&self) -> Result<WebSocketSendStream, FrameworkError> {
+        let mut state = self.open()?;
+        let id = Self::next_id(&mut state);
+        self.send(Packet::Open { id, bi: false })?;
+        Ok(self.writer(&mut state, id))
+    }
+
+    async fn accept_uni(&self) -> Result<WebSocketRecvStream, FrameworkError> {
+        self.accept(|inbox| &mut inbox.uni).await
+    }
+
+    async fn send_datagram(&self, datagram: Bytes) -> Result<(), FrameworkError> {
+        drop(self.open()?);
+        poll_fn(|cx| self.outbox.poll_room(cx)).await?;
+        self.send(Packet::Datagram(datagram.to_vec()))
+    }
+
+    async fn recv_datagram(&self) -> Result<Bytes, FrameworkError> {
+        self.accept(|inbox| &mut inbox.datagrams).await
+    }
+
+    fn close(&self, code: u32, reason: &str) {
+        let reason = reason.to_string();
+        let _ = self.send(Packet::Close {
+            code,
+            reason: reason.clone(),
+        });
+        self.end(SessionEnd::Closed { code, reason });
+    }
+}
+
+/// Messages waiting to be written to the socket. Stream data and datagrams wait for room, so a
+/// slow socket holds back the writers, while control messages always go in.
+#[derive(Default)]
+struct Outbox(Mutex<OutboxState>);
+
+#[derive(Default)]
+struct OutboxState {
+    packets: VecDeque<Packet>,
+    /// Bytes counted against `MAX_QUEUED` in `packets`
+    queued: usize,
+    /// The session has ended. What is left is still written.
+    closed: bool,
+    /// The task writing to the socket
+    sender: Option<Waker>,
+    /// Writers waiting for room
+    blocked: Vec<Waker>,
+}
+
+impl Outbox {
+    fn push(&self, packet: Packet) -> Result<(), FrameworkError> {
+        let mut outbox = self.0.lock().unwrap();
+        if outbox.closed {
+            return Err(FrameworkError::Transport("WebSocket closed".into()));
+        }
+        outbox.queued += packet.queued_len();
+        outbox.packets.push_back(packet);
+        if let Some(sender) = outbox.sender.take() {
+            sender.wake();
+        }
+        Ok(())
+    }
+
+    /// Ready once there is room for more data, or the session has ended
+    fn poll_room(&self, cx: &mut Context<'_>) -> Poll<Result<(), FrameworkError>> {
+        let mut outbox = self.0.lock().unwrap();
+        if outbox.closed {
+            return Poll::Ready(Err(FrameworkError::Transport("WebSocket closed".into())));
+        }
+        if outbox.queued < MAX_QUEUED {
+            return Poll::Ready(Ok(()));
+        }
+        outbox.blocked.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Takes the next message to write. `None` once closed and empty.
+    fn poll_pop(&self, cx: &mut Context<'_>) -> Poll<Option<Packet>> {
+        let mut outbox = self.0.lock().unwrap();
+        if let Some(packet) = outbox.packets.pop_front() {
+            outbox.queued -= packet.queued_len();
+            if outbox.queued < MAX_QUEUED {
+                outbox.blocked.drain(..).for_each(Waker::wake);
+            }
+            return Poll::Ready(Some(packet));
+        }
+        if outbox.closed {
+            return Poll::Ready(None);
+        }
+        outbox.sender = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn close(&self) {
+        let mut outbox = self.0.lock().unwrap();
+        outbox.closed = true;
+        outbox.blocked.drain(..).for_each(Waker::wake);
+        if let Some(sender) = outbox.sender.take() {
+            sender.wake();
+        }
+    }
+}
+
+/// Sending half of a stream as the session sees it
+struct Writer {
+    pipe: Pipe,
+    window: Mutex<Window>,
+}
+
+struct Window {
+    /// Bytes the peer has room for
+    credit: usize,
+    blocked: Option<Waker>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self {
+            pipe: Pipe::default(),
+            window: Mutex::new(Window {
+                credit: STREAM_WINDOW,
+                blocked: None,
+            }),
+        }
+    }
+
+    fn grant(&self, bytes: usize) {
+        let mut window = self.window.lock().unwrap();
+        window.credit = window.credit.saturating_add(bytes);
+        if let Some(blocked) = window.blocked.take() {
+            blocked.wake();
+        }
+    }
+
+    fn stop(&self, code: u32) {
+        self.pipe.stop(code);
+        self.wake();
+    }
+
+    fn end(&self, end: &SessionEnd) {
+        self.pipe.end(end);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(blocked) = self.window.lock().unwrap().blocked.take() {
+            blocked.wake();
+        }
+    }
+
+    /// Takes up to `max` bytes of credit, waiting for the peer to grant some if there is none
+    fn poll_credit(&self, cx: &mut Context<'_>, max: usize) -> Poll<Result<usize, FrameworkError>> {
+        // Checked under the lock, so stopping the stream can't slip in before we wait
+        let mut window = self.window.lock().unwrap();
+        self.pipe.check_writable()?;
+        if window.credit == 0 {
+            window.blocked = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let bytes = window.credit.min(max);
+        window.credit -= bytes;
+        Poll::Ready(Ok(bytes))
+    }
+}
+
+/// Sending half of a stream on a `WebSocketSession`. Writes wait for the peer to read what was
+/// sent before, and for room in the socket's queue.
+pub struct WebSocketSendStream {
+    id: u64,
+    writer: Arc<Writer>,
+    session: WebSocketSession,
+}
+
+/// Receiving half of a stream on a `WebSocketSession`
+pub struct WebSocketRecvStream {
+    id: u64,
+    pipe: Arc<Pipe>,
+    /// Bytes read which the peer hasn't been granted again yet
+    unacknowledged: usize,
+    session: WebSocketSession,
+}
+
+impl SendStream for WebSocketSendStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, FrameworkError> {
+        let max = buf.len().min(MAX_CHUNK_SIZE);
+        poll_fn(|cx| {
+            // Credit is only taken once the data can be queued, so cancelling loses none
+            futures::ready!(self.session.outbox.poll_room(cx))?;
+            let len = futures::ready!(self.writer.poll_credit(cx, max))?;
+            self.session.send(Packet::Data {
+                id: self.id,
+                data: buf[..len].to_vec(),
+            })?;
+            Poll::Ready(Ok(len))
+        })
+        .await
+    }
+}
+
+impl Drop for WebSocketSendStream {
+    fn drop(&mut self) {
+        self.session.state.lock().unwrap().writers.remove(&self.id);
+        let _ = self.session.send(Packet::Finish { id: self.id });
+    }
+}
+
+impl RecvStream for WebSocketRecvStream {
+    async fn read(&mut self, max: usize) -> Result<Option<Bytes>, FrameworkError> {
+        let read = poll_fn(|cx| self.pipe.poll_read(cx, max)).await?;
+        if let Some(bytes) = &read {
+            // In batches, rather than a message for every read
+            self.unacknowledged += bytes.len();
+            if self.unacknowledged >= STREAM_WINDOW / 2 {
+                let bytes = std::mem::take(&mut self.unacknowledged);
+                self.session.grant(self.id, bytes);
+            }
+        }
+        Ok(read)
+    }
+
+    fn stop(&mut self, code: u32) {
+        self.pipe.stop(code);
+        let open = self.session.state.lock().unwrap().readers.remove(&self.id);
+        if open.is_some() {
+            let _ = self.session.send(Packet::Stop { id: self.id, code });
+        }
+    }
+}
+
+impl Drop for WebSocketRecvStream {
+    fn drop(&mut self) {
+        // Like QUIC, tell the sender nobody is listening anymore
+        if !self.pipe.is_finished() {
+            self.stop(0);
+        }
+    }
+}
+
+fn transport_error(e: impl Display) -> FrameworkError {
+    FrameworkError::Transport(e.to_string())
+}
+
+/// Connects to a WebSocket server, e.g. `ws://localhost:9091`
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn connect(url: &str) -> Result<WebSocketSession, FrameworkError> {
+    let (socket, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(transport_error)?;
+    Ok(WebSocketSession::new(Role::Client, binary_messages(socket)))
+}
+
+/// Accepts a WebSocket connection from a client, on a stream such as a `TcpStream` from a
+/// `TcpListener`. The peer info has the path, query and headers of the upgrade request, but
+/// not the remote address, which only the listener knows.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn accept<S>(stream: S) -> Result<(WebSocketSession, PeerInfo), FrameworkError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+
+    let mut peer_info = PeerInfo::new();
+    let capture = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        peer_info.path = request.uri().path().to_string();
+        peer_info.query = request.uri().query().map(str::to_string);
+        peer_info.headers = request
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.to_string(), value)
+            })
+            .collect();
+        Ok(response)
+    };
+
+    let socket = tokio_tungstenite::accept_hdr_async(stream, capture)
+        .await
+        .map_err(transport_error)?;
+    let sess = WebSocketSession::new(Role::Server, binary_messages(socket));
+    Ok((sess, peer_info))
+}
+
+/// The connections coming in on a listener, each of which accepts its WebSocket when awaited,
+/// for `server::serve()`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn incoming(
+    listener: tokio::net::TcpListener,
+) -> impl Stream<
+    Item = impl futures::Future<Output = Result<(WebSocketSession, PeerInfo), FrameworkError>>,
+> {
+    futures::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await;
+        let connecting = async move {
+            let (stream, remote_addr) = accepted?;
+            let (sess, mut peer_info) = accept(stream).await?;
+            peer_info.remote_addr = Some(remote_addr);
+            Ok((sess, peer_info))
+        };
+        Some((connecting, listener))
+    })
+}
+
+/// Skips everything but binary messages, which are all the session sends
+#[cfg(not(target_arch = "wasm32"))]
+fn binary_messages<S>(
+    socket: S,
+) -> impl Sink<Bytes, Error = tungstenite::Error> + Stream<Item = Result<Bytes, tungstenite::Error>>
+where
+    S: Sink<tungstenite::Message, Error = tungstenite::Error>
+        + Stream<Item = Result<tungstenite::Message, tungstenite::Error>>,
+{
+    socket
+        .with(|data: Bytes| {
+            ready(Ok::<_, tungstenite::Error>(tungstenite::Message::Binary(
+                data,
+            )))
+        })
+        .try_filter_map(|msg| {
+            ready(Ok(match msg {
+                tungstenite::Message::Binary(data) => Some(data),
+                _ => None,
+            }))
+        })
+}
+
+/// Connects to a WebSocket server, e.g. `ws://localhost:9091`
+#[cfg(target_arch = "wasm32")]
+pub async fn connect(url: &str) -> Result<WebSocketSession, FrameworkError> {
+    use gloo_net::websocket::{futures::WebSocket, Message, WebSocketError};
+
+    let socket = WebSocket::open(url)
+        .map_err(transport_error)?
+        .with(|data: Bytes| ready(Ok::<_, WebSocketError>(Message::Bytes(data.to_vec()))))
+        .try_filter_map(|msg| {
+            ready(Ok(match msg {
+                Message::Bytes(data) => Some(Bytes::from(data)),
+                Message::Text(_) => None,
+            }))
+        });
+    Ok(WebSocketSession::new(Role::Client, socket))
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::pin::Pin;
+    use std::time::Duration;
+
+    use futures::channel::mpsc;
+
+    use super::*;
+
+    /// One end of an in-process socket
+    struct Socket {
+        tx: mpsc::Sender<Bytes>,
+        rx: mpsc::Receiver<Bytes>,
+    }
+
+    impl Sink<Bytes> for Socket {
+        type Error = mpsc::SendError;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.tx).poll_ready(cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+            Pin::new(&mut self.tx).start_send(item)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.tx).poll_flush(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.tx).poll_close(cx)
+        }
+    }
+
+    impl Stream for Socket {
+        type Item = Result<Bytes, mpsc::SendError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx).map(|msg| msg.map(Ok))
+        }
+    }
+
+    /// Both ends of a socket, each holding up to `capacity` messages nobody has read yet
+    fn sockets(capacity: usize) -> (Socket, Socket) {
+        let (client_tx, server_rx) = mpsc::channel(capacity);
+        let (server_tx, client_rx) = mpsc::channel(capacity);
+        let client = Socket {
+            tx: client_tx,
+            rx: client_rx,
+        };
+        let server = Socket {
+            tx: server_tx,
+            rx: server_rx,
+        };
+        (client, server)
+    }
+
+    fn pair() -> (WebSocketSession, WebSocketSession) {
+        let (client, server) = sockets(64);
+        (
+            WebSocketSession::new(Role::Client, client),
+            WebSocketSession::new(Role::Server, server),
+        )
+    }
+
+    /// A server session, and the raw socket of its client to send it anything from
+    fn server() -> (WebSocketSession, Socket) {
+        let (client, server) = sockets(0);
+        (WebSocketSession::new(Role::Server, server), client)
+    }
+
+    async fn send(socket: &mut Socket, packet: Packet) {
+        socket.send(encode(&packet).unwrap().into()).await.unwrap();
+    }
+
+    async fn receive(socket: &mut Socket) -> Packet {
+        decode(&socket.rx.next().await.unwrap()).unwrap()
+    }
+
+    /// Whether `fut` is still waiting after a while
+    async fn waits<F: std::future::Future>(fut: F) -> bool {
+        tokio::time::timeout(Duration::from_millis(100), fut)
+            .await
+            .is_err()
+    }
+
+    async fn read_all(rx: &mut WebSocketRecvStream) -> Vec<u8> {
+        let mut data = vec![];
+        while let Some(bytes) = rx.read(usize::MAX).await.unwrap() {
+            data.extend(bytes);
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn streams_go_both_ways_and_finish() {
+        let (client, server) = pair();
+
+        let (mut tx, mut rx) = client.open_bi().await.unwrap();
+        tx.write(b"ping").await.unwrap();
+        drop(tx);
+
+        let (mut server_tx, mut server_rx) = server.accept_bi().await.unwrap();
+        assert_eq!(read_all(&mut server_rx).await, b"ping");
+        server_tx.write(b"pong").await.unwrap();
+        drop(server_tx);
+        assert_eq!(read_all(&mut rx).await, b"pong");
+
+        let mut tx = server.open_uni().await.unwrap();
+        tx.write(b"one way").await.unwrap();
+        drop(tx);
+        let mut rx = client.accept_uni().await.unwrap();
+        assert_eq!(read_all(&mut rx).await, b"one way");
+
+        client
+            .send_datagram(Bytes::from_static(b"datagram"))
+            .await
+            .unwrap();
+        assert_eq!(server.recv_datagram().await.unwrap(), &b"datagram"[..]);
+    }
+
+    #[tokio::test]
+    async fn stopping_resets_the_writer() {
+        let (client, server) = pair();
+
+        let mut tx = client.open_uni().await.unwrap();
+        tx.write(b"unwanted").await.unwrap();
+        server.accept_uni().await.unwrap().stop(7);
+
+        loop {
+            match tx.write(b"more").await {
+                Ok(_) => crate::sleep(Duration::from_millis(5)).await,
+                Err(FrameworkError::StreamReset { code }) => break assert_eq!(code, 7),
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn closing_ends_both_sessions() {
+        let (client, server) = pair();
+        let (mut tx, _rx) = client.open_bi().await.unwrap();
+        let (_server_tx, mut server_rx) = server.accept_bi().await.unwrap();
+
+        client.close(3, "bye");
+
+        let closed = |result| matches!(result, Err(FrameworkError::Closed { code: 3, reason }) if reason == "bye");
+        assert!(closed(server_rx.read(1).await.map(drop)));
+        assert!(closed(server.accept_bi().await.map(drop)));
+        assert!(closed(client.open_uni().await.map(drop)));
+        assert!(tx.write(b"late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn writers_wait_for_the_reader() {
+        let (client, server) = pair();
+
+        let mut tx = client.open_uni().await.unwrap();
+        let chunk = vec![0; MAX_CHUNK_SIZE];
+        let mut sent = 0;
+        while sent < STREAM_WINDOW {
+            sent += tx.write(&chunk).await.unwrap();
+        }
+        assert_eq!(sent, STREAM_WINDOW);
+        assert!(waits(tx.write(&chunk)).await);
+
+        // Reading half the window grants it back
+        let mut rx = server.accept_uni().await.unwrap();
+        let mut read = 0;
+        while read < STREAM_WINDOW / 2 {
+            read += rx.read(usize::MAX).await.unwrap().unwrap().len();
+        }
+        assert!(!waits(tx.write(&chunk)).await);
+    }
+
+    #[tokio::test]
+    async fn the_outbox_holds_back_writers_once_full() {
+        let (sess, mut client) = server();
+
+        // Nobody reads the socket, so everything but the message being sent stays queued
+        let datagram = Bytes::from(vec![0; MAX_CHUNK_SIZE]);
+        let mut sent = 0;
+        while !waits(sess.send_datagram(datagram.clone())).await {
+            sent += 1;
+        }
+        assert!(sent * MAX_CHUNK_SIZE >= MAX_QUEUED);
+        assert!(sent * MAX_CHUNK_SIZE <= MAX_QUEUED + 2 * MAX_CHUNK_SIZE);
+
+        // Control messages still go in
+        let mut tx = sess.open_uni().await.unwrap();
+        assert!(waits(tx.write(&datagram)).await);
+
+        receive(&mut client).await;
+        receive(&mut client).await;
+        assert!(!waits(sess.send_datagram(datagram)).await);
+    }
+
+    #[tokio::test]
+    async fn the_peer_can_only_open_its_own_ids() {
+        let (sess, mut client) = server();
+        send(&mut client, Packet::Open { id: 1, bi: true }).await;
+        assert!(matches!(
+            sess.accept_bi().await,
+            Err(FrameworkError::Transport(_))
+        ));
+
+        let (sess, mut client) = server();
+        send(&mut client, Packet::Open { id: 4, bi: false }).await;
+        sess.accept_uni().await.unwrap();
+        send(&mut client, Packet::Open { id: 2, bi: false }).await;
+        assert!(matches!(
+            sess.accept_uni().await,
+            Err(FrameworkError::Transport(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn streams_over_the_limit_are_refused() {
+        let (sess, mut client) = server();
+
+        for i in 0..=MAX_REMOTE_STREAMS as u64 {
+            send(
+                &mut client,
+                Packet::Open {
+                    id: 2 * i,
+                    bi: true,
+                },
+            )
+            .await;
+        }
+        let refused = 2 * MAX_REMOTE_STREAMS as u64;
+        assert!(matches!(
+            receive(&mut client).await,
+            Packet::Stop { id, code: STREAM_REFUSED_CODE } if id == refused
+        ));
+        assert!(matches!(
+            receive(&mut client).await,
+            Packet::Finish { id } if id == refused
+        ));
+
+        // Once one has closed, there is room for another
+        drop(sess.accept_bi().await.unwrap());
+        for _ in 0..2 {
+            assert!(matches!(
+                receive(&mut client).await,
+                Packet::Finish { id: 0 } | Packet::Stop { id: 0, .. }
+            ));
+        }
+        send(
+            &mut client,
+            Packet::Open {
+                id: refused + 2,
+                bi: false,
+            },
+        )
+        .await;
+        assert!(waits(receive(&mut client)).await);
+    }
+}
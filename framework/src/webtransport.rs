@@ -0,0 +1,72 @@
+//! Accepting WebTransport sessions on a server, with what is known about each client
+
+use futures::{Future, Stream};
+
+use crate::io::FrameworkError;
+use crate::peer_info::PeerInfo;
+
+/// Accepts a WebTransport session, e.g. from an endpoint made by
+/// `quic_session::server_endpoint()`. The peer info has the remote address, the path and query
+/// of the URL, the ALPN, and the pseudo-headers of the CONNECT request (`:method`, `:protocol`,
+/// `:scheme`, `:authority` and `:path`). web-transport-quinn only keeps the URL it parses from
+/// those, which they are rebuilt from, and drops the other headers, such as `Origin`.
+pub async fn accept(
+    request: web_transport_quinn::Request,
+) -> Result<(web_transport::Session, PeerInfo), FrameworkError> {
+    let url = request.url().clone();
+    let mut authority = url.host_str().unwrap_or_default().to_string();
+    if let Some(port) = url.port() {
+        authority.push_str(&format!(":{port}"));
+    }
+    let path = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+    let headers = [
+        (":method", "CONNECT".to_string()),
+        (":protocol", "webtransport".to_string()),
+        (":scheme", url.scheme().to_string()),
+        (":authority", authority),
+        (":path", path),
+    ];
+
+    let session = request
+        .ok()
+        .await
+        .map_err(|e| FrameworkError::Transport(e.to_string()))?;
+
+    let peer_info = PeerInfo {
+        remote_addr: Some(session.remote_address()),
+        path: url.path().to_string(),
+        query: url.query().map(str::to_string),
+        headers: headers
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+        alpn: negotiated_alpn(&session),
+        ..PeerInfo::new()
+    };
+
+    Ok((session.into(), peer_info))
+}
+
+/// The sessions coming in on an endpoint, each of which is accepted when awaited, for
+/// `server::serve()`
+pub fn incoming(
+    endpoint: web_transport_quinn::Server,
+) -> impl Stream<Item = impl Future<Output = Result<(web_transport::Session, PeerInfo), FrameworkError>>>
+{
+    futures::stream::unfold(endpoint, |mut endpoint| async move {
+        let request = endpoint.accept().await?;
+        Some((accept(request), endpoint))
+    })
+}
+
+fn negotiated_alpn(conn: &quinn::Connection) -> Option<String> {
+    let data = conn
+        .handshake_data()?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?;
+    let protocol = data.protocol?;
+    Some(String::from_utf8_lossy(&protocol).into_owned())
+}